@@ -13,8 +13,6 @@ while ! timeout 10 curl -X POST --silent http://cpen442coin.ece.ubc.ca/last_coin
     echo "Checking CPEN442 Coin is Working..."
 done
 
-#cp -v all_proxies.txt proxy_in.txt
-
 opts=(
     --identity 00ed0d3d8577f6c8c654c8c4527ce884
     --cl-device 0
@@ -23,7 +21,7 @@ opts=(
     --ncpu 2
     --poll-ms 8000
     -o wallet.txt
-    #--proxy-file proxy_in.txt
+    #--proxy-file all_proxies.txt
 )
 
 exec ./target/release/cpen442-miner "${opts[@]}" "$@"
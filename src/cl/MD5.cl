@@ -14,86 +14,123 @@
 
 #define GET(i) (message[(i)])
 
-// void md5_round(uint* internal_state, const uint* message);
+// All 64 steps of a MD5 block on a, b, c and d, EARLY_CHECK runs right after
+// step 60 which is the last step that changes a. The first word of the hash is
+// a added to the old state so it is known exactly from there, steps 61 to 63 only
+// change d, c and b. It can't run sooner, before step 60 a isn't its final value
+// and nothing about the final value can be told from it.
+#define MD5_STEPS(EARLY_CHECK) \
+  /* Round 1 */ \
+  STEP(F, a, b, c, d, GET(0), 0xd76aa478, 7) \
+  STEP(F, d, a, b, c, GET(1), 0xe8c7b756, 12) \
+  STEP(F, c, d, a, b, GET(2), 0x242070db, 17) \
+  STEP(F, b, c, d, a, GET(3), 0xc1bdceee, 22) \
+  STEP(F, a, b, c, d, GET(4), 0xf57c0faf, 7) \
+  STEP(F, d, a, b, c, GET(5), 0x4787c62a, 12) \
+  STEP(F, c, d, a, b, GET(6), 0xa8304613, 17) \
+  STEP(F, b, c, d, a, GET(7), 0xfd469501, 22) \
+  STEP(F, a, b, c, d, GET(8), 0x698098d8, 7) \
+  STEP(F, d, a, b, c, GET(9), 0x8b44f7af, 12) \
+  STEP(F, c, d, a, b, GET(10), 0xffff5bb1, 17) \
+  STEP(F, b, c, d, a, GET(11), 0x895cd7be, 22) \
+  STEP(F, a, b, c, d, GET(12), 0x6b901122, 7) \
+  STEP(F, d, a, b, c, GET(13), 0xfd987193, 12) \
+  STEP(F, c, d, a, b, GET(14), 0xa679438e, 17) \
+  STEP(F, b, c, d, a, GET(15), 0x49b40821, 22) \
+  \
+  /* Round 2 */ \
+  STEP(G, a, b, c, d, GET(1), 0xf61e2562, 5) \
+  STEP(G, d, a, b, c, GET(6), 0xc040b340, 9) \
+  STEP(G, c, d, a, b, GET(11), 0x265e5a51, 14) \
+  STEP(G, b, c, d, a, GET(0), 0xe9b6c7aa, 20) \
+  STEP(G, a, b, c, d, GET(5), 0xd62f105d, 5) \
+  STEP(G, d, a, b, c, GET(10), 0x02441453, 9) \
+  STEP(G, c, d, a, b, GET(15), 0xd8a1e681, 14) \
+  STEP(G, b, c, d, a, GET(4), 0xe7d3fbc8, 20) \
+  STEP(G, a, b, c, d, GET(9), 0x21e1cde6, 5) \
+  STEP(G, d, a, b, c, GET(14), 0xc33707d6, 9) \
+  STEP(G, c, d, a, b, GET(3), 0xf4d50d87, 14) \
+  STEP(G, b, c, d, a, GET(8), 0x455a14ed, 20) \
+  STEP(G, a, b, c, d, GET(13), 0xa9e3e905, 5) \
+  STEP(G, d, a, b, c, GET(2), 0xfcefa3f8, 9) \
+  STEP(G, c, d, a, b, GET(7), 0x676f02d9, 14) \
+  STEP(G, b, c, d, a, GET(12), 0x8d2a4c8a, 20) \
+  \
+  /* Round 3 */ \
+  STEP(H, a, b, c, d, GET(5), 0xfffa3942, 4) \
+  STEP(H, d, a, b, c, GET(8), 0x8771f681, 11) \
+  STEP(H, c, d, a, b, GET(11), 0x6d9d6122, 16) \
+  STEP(H, b, c, d, a, GET(14), 0xfde5380c, 23) \
+  STEP(H, a, b, c, d, GET(1), 0xa4beea44, 4) \
+  STEP(H, d, a, b, c, GET(4), 0x4bdecfa9, 11) \
+  STEP(H, c, d, a, b, GET(7), 0xf6bb4b60, 16) \
+  STEP(H, b, c, d, a, GET(10), 0xbebfbc70, 23) \
+  STEP(H, a, b, c, d, GET(13), 0x289b7ec6, 4) \
+  STEP(H, d, a, b, c, GET(0), 0xeaa127fa, 11) \
+  STEP(H, c, d, a, b, GET(3), 0xd4ef3085, 16) \
+  STEP(H, b, c, d, a, GET(6), 0x04881d05, 23) \
+  STEP(H, a, b, c, d, GET(9), 0xd9d4d039, 4) \
+  STEP(H, d, a, b, c, GET(12), 0xe6db99e5, 11) \
+  STEP(H, c, d, a, b, GET(15), 0x1fa27cf8, 16) \
+  STEP(H, b, c, d, a, GET(2), 0xc4ac5665, 23) \
+  \
+  /* Round 4 */ \
+  STEP(I, a, b, c, d, GET(0), 0xf4292244, 6) \
+  STEP(I, d, a, b, c, GET(7), 0x432aff97, 10) \
+  STEP(I, c, d, a, b, GET(14), 0xab9423a7, 15) \
+  STEP(I, b, c, d, a, GET(5), 0xfc93a039, 21) \
+  STEP(I, a, b, c, d, GET(12), 0x655b59c3, 6) \
+  STEP(I, d, a, b, c, GET(3), 0x8f0ccc92, 10) \
+  STEP(I, c, d, a, b, GET(10), 0xffeff47d, 15) \
+  STEP(I, b, c, d, a, GET(1), 0x85845dd1, 21) \
+  STEP(I, a, b, c, d, GET(8), 0x6fa87e4f, 6) \
+  STEP(I, d, a, b, c, GET(15), 0xfe2ce6e0, 10) \
+  STEP(I, c, d, a, b, GET(6), 0xa3014314, 15) \
+  STEP(I, b, c, d, a, GET(13), 0x4e0811a1, 21) \
+  STEP(I, a, b, c, d, GET(4), 0xf7537e82, 6) \
+  EARLY_CHECK \
+  STEP(I, d, a, b, c, GET(11), 0xbd3af235, 10) \
+  STEP(I, c, d, a, b, GET(2), 0x2ad7d2bb, 15) \
+  STEP(I, b, c, d, a, GET(9), 0xeb86d391, 21)
+
+// uint md5_round_ex(uint* internal_state, const uint* message, const uint early_reject);
 // Credit to https://github.com/awreece/pdfcrack-opencl for the MD5 algorithm
-static inline void md5_round(uint* internal_state, const uint* message) {
+//
+// With early_reject set this gives up and returns 0 right after step 60
+// when the first word of the new state won't be 0, skipping the last
+// 3 steps and the additions. Otherwise the state is updated and 1 returned.
+static inline uint md5_round_ex(uint* internal_state, const uint* message, const uint early_reject) {
   uint a, b, c, d;
   a = internal_state[0];
   b = internal_state[1];
   c = internal_state[2];
   d = internal_state[3];
 
-  /* Round 1 */
-  STEP(F, a, b, c, d, GET(0), 0xd76aa478, 7)
-  STEP(F, d, a, b, c, GET(1), 0xe8c7b756, 12)
-  STEP(F, c, d, a, b, GET(2), 0x242070db, 17)
-  STEP(F, b, c, d, a, GET(3), 0xc1bdceee, 22)
-  STEP(F, a, b, c, d, GET(4), 0xf57c0faf, 7)
-  STEP(F, d, a, b, c, GET(5), 0x4787c62a, 12)
-  STEP(F, c, d, a, b, GET(6), 0xa8304613, 17)
-  STEP(F, b, c, d, a, GET(7), 0xfd469501, 22)
-  STEP(F, a, b, c, d, GET(8), 0x698098d8, 7)
-  STEP(F, d, a, b, c, GET(9), 0x8b44f7af, 12)
-  STEP(F, c, d, a, b, GET(10), 0xffff5bb1, 17)
-  STEP(F, b, c, d, a, GET(11), 0x895cd7be, 22)
-  STEP(F, a, b, c, d, GET(12), 0x6b901122, 7)
-  STEP(F, d, a, b, c, GET(13), 0xfd987193, 12)
-  STEP(F, c, d, a, b, GET(14), 0xa679438e, 17)
-  STEP(F, b, c, d, a, GET(15), 0x49b40821, 22)
-
-  /* Round 2 */
-  STEP(G, a, b, c, d, GET(1), 0xf61e2562, 5)
-  STEP(G, d, a, b, c, GET(6), 0xc040b340, 9)
-  STEP(G, c, d, a, b, GET(11), 0x265e5a51, 14)
-  STEP(G, b, c, d, a, GET(0), 0xe9b6c7aa, 20)
-  STEP(G, a, b, c, d, GET(5), 0xd62f105d, 5)
-  STEP(G, d, a, b, c, GET(10), 0x02441453, 9)
-  STEP(G, c, d, a, b, GET(15), 0xd8a1e681, 14)
-  STEP(G, b, c, d, a, GET(4), 0xe7d3fbc8, 20)
-  STEP(G, a, b, c, d, GET(9), 0x21e1cde6, 5)
-  STEP(G, d, a, b, c, GET(14), 0xc33707d6, 9)
-  STEP(G, c, d, a, b, GET(3), 0xf4d50d87, 14)
-  STEP(G, b, c, d, a, GET(8), 0x455a14ed, 20)
-  STEP(G, a, b, c, d, GET(13), 0xa9e3e905, 5)
-  STEP(G, d, a, b, c, GET(2), 0xfcefa3f8, 9)
-  STEP(G, c, d, a, b, GET(7), 0x676f02d9, 14)
-  STEP(G, b, c, d, a, GET(12), 0x8d2a4c8a, 20)
-
-  /* Round 3 */
-  STEP(H, a, b, c, d, GET(5), 0xfffa3942, 4)
-  STEP(H, d, a, b, c, GET(8), 0x8771f681, 11)
-  STEP(H, c, d, a, b, GET(11), 0x6d9d6122, 16)
-  STEP(H, b, c, d, a, GET(14), 0xfde5380c, 23)
-  STEP(H, a, b, c, d, GET(1), 0xa4beea44, 4)
-  STEP(H, d, a, b, c, GET(4), 0x4bdecfa9, 11)
-  STEP(H, c, d, a, b, GET(7), 0xf6bb4b60, 16)
-  STEP(H, b, c, d, a, GET(10), 0xbebfbc70, 23)
-  STEP(H, a, b, c, d, GET(13), 0x289b7ec6, 4)
-  STEP(H, d, a, b, c, GET(0), 0xeaa127fa, 11)
-  STEP(H, c, d, a, b, GET(3), 0xd4ef3085, 16)
-  STEP(H, b, c, d, a, GET(6), 0x04881d05, 23)
-  STEP(H, a, b, c, d, GET(9), 0xd9d4d039, 4)
-  STEP(H, d, a, b, c, GET(12), 0xe6db99e5, 11)
-  STEP(H, c, d, a, b, GET(15), 0x1fa27cf8, 16)
-  STEP(H, b, c, d, a, GET(2), 0xc4ac5665, 23)
-
-  /* Round 4 */
-  STEP(I, a, b, c, d, GET(0), 0xf4292244, 6)
-  STEP(I, d, a, b, c, GET(7), 0x432aff97, 10)
-  STEP(I, c, d, a, b, GET(14), 0xab9423a7, 15)
-  STEP(I, b, c, d, a, GET(5), 0xfc93a039, 21)
-  STEP(I, a, b, c, d, GET(12), 0x655b59c3, 6)
-  STEP(I, d, a, b, c, GET(3), 0x8f0ccc92, 10)
-  STEP(I, c, d, a, b, GET(10), 0xffeff47d, 15)
-  STEP(I, b, c, d, a, GET(1), 0x85845dd1, 21)
-  STEP(I, a, b, c, d, GET(8), 0x6fa87e4f, 6)
-  STEP(I, d, a, b, c, GET(15), 0xfe2ce6e0, 10)
-  STEP(I, c, d, a, b, GET(6), 0xa3014314, 15)
-  STEP(I, b, c, d, a, GET(13), 0x4e0811a1, 21)
-  STEP(I, a, b, c, d, GET(4), 0xf7537e82, 6)
-  STEP(I, d, a, b, c, GET(11), 0xbd3af235, 10)
-  STEP(I, c, d, a, b, GET(2), 0x2ad7d2bb, 15)
-  STEP(I, b, c, d, a, GET(9), 0xeb86d391, 21)
+  MD5_STEPS(if (early_reject && a + internal_state[0] != 0) { return 0; })
+
+  internal_state[0] = a + internal_state[0];
+  internal_state[1] = b + internal_state[1];
+  internal_state[2] = c + internal_state[2];
+  internal_state[3] = d + internal_state[3];
+
+  return 1;
+}
+
+// void md5_round(uint* internal_state, const uint* message);
+static inline void md5_round(uint* internal_state, const uint* message) {
+  md5_round_ex(internal_state, message, 0);
+}
+
+// void md5_round4(uint4* internal_state, const uint4* message);
+// 4 independent MD5 blocks at once, one per vector lane
+static inline void md5_round4(uint4* internal_state, const uint4* message) {
+  uint4 a, b, c, d;
+  a = internal_state[0];
+  b = internal_state[1];
+  c = internal_state[2];
+  d = internal_state[3];
+
+  MD5_STEPS()
 
   internal_state[0] = a + internal_state[0];
   internal_state[1] = b + internal_state[1];
@@ -150,11 +187,71 @@ static inline void md5_round(uint* internal_state, const uint* message) {
 #define N_LOOPS_2 256
 #endif
 
+// Leading MD5 blocks the host hashed, nothing in them may change per work-item
+#ifndef MIDSTATE_BLOCKS
+#define MIDSTATE_BLOCKS 0
+#endif
+
+// Set to check the first hash word a few steps before the end of the last block
+#ifndef EARLY_REJECT
+#define EARLY_REJECT 0
+#endif
+
+// Counters hashed at once by each work-item in the inner loop, 1 or 4
+#ifndef VECTOR_WIDTH
+#define VECTOR_WIDTH 1
+#endif
+
+// Number of (id, i, j) results that fit in params_out
+#ifndef MAX_RESULTS
+#define MAX_RESULTS 16
+#endif
+
+// Where the results end and the debug output starts in params_out
+#define RESULTS_LEN (1 + 3 * MAX_RESULTS)
+
 // Check endianess as this program is only good for little endian
 #ifndef __LITTLE_ENDIAN__
 #error This kernel currently only supports little endian architectures!
 #endif
 
+// Save a hit (id, i, j) to the next free slot of params_out
+static inline void record_hit(__global uint* params_out, uint id, uint i, uint j,
+    const uint* md5_state, const uint* message) {
+  uint slot = atomic_inc(&params_out[0]);
+
+  if (slot < MAX_RESULTS) {
+    params_out[1 + 3 * slot] = id;
+    params_out[2 + 3 * slot] = i;
+    params_out[3 + 3 * slot] = j;
+  }
+
+#ifdef __DEBUG_MODE__
+  if (slot == 0) {
+    uint k;
+
+    for (k = 0; k < 4; ++k) {
+      params_out[RESULTS_LEN + k] = md5_state[k];
+    }
+
+    for (k = 0; k < MESSAGE_LEN; ++k) {
+      params_out[RESULTS_LEN + 4 + k] = message[k];
+    }
+  }
+#endif
+}
+
+#if VECTOR_WIDTH == 4
+// Check one lane of the vector state for a hit
+#define CHECK_LANE(lane, k) \
+  if (md5_state4[0].lane == 0 && (md5_state4[1].lane & word2_mask) == 0) { \
+    uint lane_state[4] = { md5_state4[0].lane, md5_state4[1].lane, \
+      md5_state4[2].lane, md5_state4[3].lane }; \
+    message[LAST_ROUND_COUNTER_INDEX] = counter4.lane; \
+    record_hit(params_out, id, i, j + (k), lane_state, message); \
+  }
+#endif
+
 /**
  * Parallel MD5 hash kernel
  *
@@ -168,14 +265,16 @@ static inline void md5_round(uint* internal_state, const uint* message) {
  *
  * In host post processing one can reproduce the message
  *
+ * params_out[0] counts the hits and each hit claims the next
+ * (id, i, j) slot after it with an atomic increment, so no hit
+ * overwrites another. Hits past MAX_RESULTS are only counted.
  */
 __kernel void md5(
     // The base message to hash
     __constant uint* base_message,
-    // Extra random values
+    // Word 2 mask, extra random values then the MD5 state after the first MIDSTATE_BLOCKS blocks
     __constant uint* params_in,
-    // The parameters output to the program
-    // Note that many processing units may try to write to this location
+    // The hit count followed by the hits, zeroed by the host
     __global uint* params_out) {
   uint i;
   uint j;
@@ -210,12 +309,23 @@ __kernel void md5(
   uint md5_state[4];
   uint md5_state_2[4];
 
+#if VECTOR_WIDTH == 4
+  uint4 message4[16];
+  uint4 zero_pad4[16];
+  uint4 md5_state4[4];
+  uint4 counter4;
+
+  for (i = 0; i < 16; ++i) {
+    zero_pad4[i] = (uint4)(zero_pad[i]);
+  }
+#endif
+
   for (i = 0; i < N_LOOPS; ++i) {
-    // Initialize MD5
-    md5_state[0] = 0x67452301;
-    md5_state[1] = 0xefcdab89;
-    md5_state[2] = 0x98badcfe;
-    md5_state[3] = 0x10325476;
+    // Start from the host's MD5 midstate
+    md5_state[0] = params_in[4];
+    md5_state[1] = params_in[5];
+    md5_state[2] = params_in[6];
+    md5_state[3] = params_in[7];
 
     // Modify the message per iteration based on ID
     message[BLOB_INDEX + (id + r0) % BLOB_LEN_FAST] = orig0 + id + i * 4;
@@ -223,10 +333,36 @@ __kernel void md5(
     message[BLOB_INDEX + BLOB_LEN_FAST] = orig2 + (id << 16) + i - r2;
 
     // Perform MD5 till before the last round
-    for (j = 0; j < MESSAGE_LEN / 16 - 1; ++j) {
+    for (j = MIDSTATE_BLOCKS; j < MESSAGE_LEN / 16 - 1; ++j) {
       md5_round(md5_state, &message[j * 16]);
     }
 
+#if VECTOR_WIDTH == 4
+    for (j = 0; j < 16; ++j) {
+      message4[j] = (uint4)(message[MESSAGE_LEN - 16 + j]);
+    }
+
+    // Same as the loop below but 4 counters at once, only the counter word differs per lane
+    for (j = 0; j < N_LOOPS_2; j += 4) {
+      const uint4 jv = (uint4)(j, j + 1, j + 2, j + 3);
+
+      md5_state4[0] = (uint4)(md5_state[0]);
+      md5_state4[1] = (uint4)(md5_state[1]);
+      md5_state4[2] = (uint4)(md5_state[2]);
+      md5_state4[3] = (uint4)(md5_state[3]);
+
+      counter4 = orig3 + (jv >> 2) + (jv << 24) + (i << 12);
+      message4[LAST_ROUND_COUNTER_INDEX - (MESSAGE_LEN - 16)] = counter4;
+
+      md5_round4(md5_state4, message4);
+      md5_round4(md5_state4, zero_pad4);
+
+      CHECK_LANE(s0, 0)
+      CHECK_LANE(s1, 1)
+      CHECK_LANE(s2, 2)
+      CHECK_LANE(s3, 3)
+    }
+#else
     for (j = 0; j < N_LOOPS_2; ++j) {
       // Don't clobber our state
       md5_state_2[0] = md5_state[0];
@@ -238,32 +374,18 @@ __kernel void md5(
 
       // Perform the last 2 rounds of MD5
       md5_round(md5_state_2, &message[MESSAGE_LEN - 16]);
-      md5_round(md5_state_2, zero_pad);
+      if (!md5_round_ex(md5_state_2, zero_pad, EARLY_REJECT)) {
+        continue;
+      }
 
       // Note skip the padding algorithm since the
       // message is already a multiple of the block size
 
       // Check if the prefix is okay
-      if (md5_state_2[0] == 0 && ((md5_state_2[1] & word2_mask) == 0)
-          // Check that another core hasn't already set the output
-          // Note that this is isn't guarenteed to prevent clobbering
-          && params_out[0] == 0xFFFFFFFF) {
-        params_out[0] = id;
-        params_out[1] = i;
-        params_out[2] = j;
-
-#ifdef __DEBUG_MODE__
-        params_out[3] = md5_state_2[0];
-        params_out[4] = md5_state_2[1];
-        params_out[5] = md5_state_2[2];
-        params_out[6] = md5_state_2[3];
-
-        for (i = 0; i < MESSAGE_LEN; ++i) {
-          params_out[7 + i] = message[i];
-        }
-#endif
-        break;
+      if (md5_state_2[0] == 0 && ((md5_state_2[1] & word2_mask) == 0)) {
+        record_hit(params_out, id, i, j, md5_state_2, message);
       }
     }
+#endif
   }
 }
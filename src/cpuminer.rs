@@ -1,106 +1,209 @@
 use crate::{
     error::Error,
-    cpen442coin::{COIN_PREFIX_STR, MD5_BLOCK_LEN},
-    miner::{Coin, Stats, MinerFunction, Miner, MinerThreadData, MinerSharedData},
+    cpen442coin::{COIN_PREFIX_STR, MD5_BLOCK_LEN, MD5_HASH_HEX_LEN},
+    md5::{self, SimdLevel, MAX_LANES, MD5_BLOCK_WORDS},
+    miner::{Coin, Stats, MinerFunction, Miner, MinerThreadData},
     util::*
 };
-use rand::{Rng, RngCore};
-use arrayvec::ArrayVec;
-use std::sync::{Arc, atomic::Ordering};
+use rand::{RngCore, rngs::OsRng};
 use std::time::{Duration, Instant};
 use openssl::hash::{Hasher, MessageDigest};
 
-const MINER_MAX_BLOCKS : usize = 8;
+// Message: ["CPEN 442..." | PREV_COIN | BLOB | MINER ID], like the OpenCL miner
+const MESSAGE_LEN : usize = MD5_BLOCK_LEN * 4;
+const MESSAGE_WORDS : usize = MESSAGE_LEN / 4;
+const BLOB_INDEX : usize = COIN_PREFIX_STR.len() + MD5_HASH_HEX_LEN;
+const BLOB_END : usize = MESSAGE_LEN - MD5_HASH_HEX_LEN;
+// Only the last block changes while a message is searched, the midstate before it is reused
+const MIDSTATE_BLOCKS : usize = 3;
+// Word of the blob set to the candidate number, the first one after the midstate
+const COUNTER_WORD : usize = MIDSTATE_BLOCKS * MD5_BLOCK_WORDS;
+// Candidates tried before the blob is randomized again
+const CANDIDATES : u32 = 1 << 14;
 
 pub type CpuMiner = Miner<CpuMinerFunction>;
 
-pub struct CpuMinerFunction {}
+pub struct CpuMinerFunction {
+    simd : Option<SimdLevel>,
+}
 
 impl CpuMinerFunction {
+    /// Uses the widest SIMD the CPU has, OpenSSL if it has none
     pub fn new() -> CpuMinerFunction {
-        CpuMinerFunction {}
+        Self::with_simd(SimdLevel::detect())
+    }
+
+    /// None hashes with OpenSSL
+    pub fn with_simd(simd : Option<SimdLevel>) -> CpuMinerFunction {
+        if let Some(level) = simd {
+            assert!(level.supported(), "{:?} is not supported by this CPU", level);
+        }
+
+        CpuMinerFunction { simd }
     }
 }
 
-impl MinerFunction for CpuMinerFunction {
-    fn run(self, tdata : MinerThreadData, tsdata: Arc<MinerSharedData>) -> Result<(), Error> {
-        let mut rng = rand::thread_rng();
-        let dist = rand::distributions::Uniform::from(0..=255);
-        let mut hasher = Hasher::new(MessageDigest::md5())?;
+/// Words of the padding block after a message
+fn padding_block() -> [u32; MD5_BLOCK_WORDS] {
+    let mut block = [0u32; MD5_BLOCK_WORDS];
+    block[0] = 0x80;
+    block[14] = (MESSAGE_LEN * 8) as u32;
+    block
+}
+
+/// Hash the message with counter word set to each of start..start + count,
+/// returns the counters whose hash starts with num_zeros zeroes
+fn simd_search(level : SimdLevel, message : &[u32; MESSAGE_WORDS], midstate : &[u32; 4],
+    start : u32, count : u32, num_zeros : u64) -> Vec<u32> {
+    let splat = |words : &[u32]| {
+        let mut block = [[0u32; MAX_LANES]; MD5_BLOCK_WORDS];
+        block.iter_mut().zip(words.iter()).for_each(|(b, w)| *b = [*w; MAX_LANES]);
+        block
+    };
 
-        let mut previous_coin = tsdata.previous_coin.take(Ordering::Relaxed).unwrap();
-        let mut num_zeros = *tsdata.difficulty.take(Ordering::Relaxed).unwrap();
+    let mut last_block = splat(&message[COUNTER_WORD - COUNTER_WORD % MD5_BLOCK_WORDS..]);
+    let padding = splat(&padding_block());
 
-        let mut suffix_bytes : ArrayVec<[u8; MD5_BLOCK_LEN]> = ArrayVec::new();
-        suffix_bytes.try_extend_from_slice(tdata.miner_id.as_bytes()).unwrap();
+    let lanes = level.lanes() as u32;
+    let mut hits = Vec::new();
+
+    for base in (start..start + count).step_by(lanes as usize) {
+        for (lane, word) in last_block[COUNTER_WORD % MD5_BLOCK_WORDS].iter_mut().enumerate() {
+            *word = base.wrapping_add(lane as u32);
+        }
 
-        let mut coin_block : ArrayVec<[u8; MD5_BLOCK_LEN * MINER_MAX_BLOCKS]> = ArrayVec::new();
+        let mut state = [[0u32; MAX_LANES]; 4];
+        state.iter_mut().zip(midstate.iter()).for_each(|(s, m)| *s = [*m; MAX_LANES]);
+
+        md5::md5_compress_lanes(level, &mut state, &last_block);
+        md5::md5_compress_lanes(level, &mut state, &padding);
+
+        for lane in 0..lanes.min(start + count - base) as usize {
+            // Most lanes are rejected by the first nibble without building the hash
+            if num_zeros > 0 && state[0][lane] & 0xF0 != 0 {
+                continue;
+            }
 
-        let mut prefix_bytes : ArrayVec<[u8; MD5_BLOCK_LEN]> = ArrayVec::new();
-        prefix_bytes.try_extend_from_slice(COIN_PREFIX_STR.as_bytes()).unwrap();
-        prefix_bytes.try_extend_from_slice(previous_coin.as_bytes()).unwrap();
+            let mut h = [0u8; 16];
+            for (w, bytes) in h.chunks_mut(4).enumerate() {
+                bytes.copy_from_slice(&state[w][lane].to_le_bytes());
+            }
+
+            if hash_starts_n_zeroes(&h, num_zeros) {
+                hits.push(base.wrapping_add(lane as u32));
+            }
+        }
+    }
+
+    hits
+}
+
+/// Same as simd_search with OpenSSL, the hasher is cloned after the blocks before the counter
+fn openssl_search(message : &[u8; MESSAGE_LEN], start : u32, count : u32, num_zeros : u64)
+    -> Result<Vec<u32>, Error> {
+    let counter_index = COUNTER_WORD * 4;
+    let mut prefix_hasher = Hasher::new(MessageDigest::md5())?;
+    prefix_hasher.update(&message[..counter_index])?;
+
+    let mut hits = Vec::new();
+
+    for counter in start..start + count {
+        let mut hasher = prefix_hasher.clone();
+        hasher.update(&counter.to_le_bytes())?;
+        hasher.update(&message[counter_index + 4..])?;
+        let h = hasher.finish()?;
+
+        if hash_starts_n_zeroes(&h[..], num_zeros) {
+            hits.push(counter);
+        }
+    }
+
+    Ok(hits)
+}
+
+/// Blob of a message with the counter word set
+fn message_blob(message : &[u8; MESSAGE_LEN], counter : u32) -> Vec<u8> {
+    let mut message = *message;
+    let counter_index = COUNTER_WORD * 4;
+    message[counter_index..counter_index + 4].copy_from_slice(&counter.to_le_bytes());
+    Vec::from(&message[BLOB_INDEX..BLOB_END])
+}
+
+impl MinerFunction for CpuMinerFunction {
+    fn run(self, tdata : MinerThreadData) -> Result<(), Error> {
+        let mut rng = rand::thread_rng();
+
+        let mut previous_coin = tdata.previous_coin.clone();
+        let mut num_zeros = tdata.difficulty;
+
+        let mut message = [0u8; MESSAGE_LEN];
+        message[..COIN_PREFIX_STR.len()].copy_from_slice(COIN_PREFIX_STR.as_bytes());
+        message[COIN_PREFIX_STR.len()..BLOB_INDEX].copy_from_slice(previous_coin.as_bytes());
+        message[BLOB_END..].copy_from_slice(tdata.miner_id.as_bytes());
 
         let start = Instant::now();
         let mut last_report_timer = Timer::new(Duration::from_millis(2000));
         let mut counter = 0;
 
-        while ! tsdata.should_stop.load(Ordering::Relaxed) {
-            if let Some(new_coin) = tsdata.previous_coin.take(Ordering::Relaxed) {
+        loop {
+            let update = tdata.updates();
+
+            if update.stop {
+                break;
+            }
+
+            if let Some(new_coin) = update.previous_coin {
                 previous_coin = new_coin;
-                unsafe { prefix_bytes.set_len(COIN_PREFIX_STR.len()); }
-                prefix_bytes.try_extend_from_slice(previous_coin.as_bytes()).unwrap();
+                message[COIN_PREFIX_STR.len()..BLOB_INDEX].copy_from_slice(previous_coin.as_bytes());
             }
 
-            if let Some(new_num_zeros) = tsdata.difficulty.take(Ordering::Relaxed) {
-                num_zeros = *new_num_zeros;
+            if let Some(new_num_zeros) = update.difficulty {
+                num_zeros = new_num_zeros;
             }
 
-            coin_block.clear();
+            let mut i = BLOB_INDEX;
             // Add timestamp
-            coin_block.try_extend_from_slice(&start.elapsed().as_nanos().to_ne_bytes()[..]).unwrap();
+            message[i..i + 16].copy_from_slice(&start.elapsed().as_nanos().to_ne_bytes()[..]);
+            i += 16;
             // openssl RNG
-            let mut b = [0u8; 16];
-            openssl::rand::rand_bytes(&mut b[..]).unwrap();
-            coin_block.try_extend_from_slice(&b[..]).unwrap();
+            openssl::rand::rand_bytes(&mut message[i..i + 16]).unwrap();
+            i += 16;
+            // OS RNG
+            OsRng.fill_bytes(&mut message[i..i + 16]);
+            i += 16;
             // rand RNG
-            let mut sample_len = rng.next_u32() as usize % (MD5_BLOCK_LEN * MINER_MAX_BLOCKS);
-            if sample_len + coin_block.len() > MD5_BLOCK_LEN * (MINER_MAX_BLOCKS - 1) {
-                sample_len -= MD5_BLOCK_LEN;
-            }
-            let cur_len = prefix_bytes.len() + coin_block.len() + sample_len + suffix_bytes.len();
-            let sample_len = cur_len + MD5_BLOCK_LEN - cur_len % MD5_BLOCK_LEN -
-                prefix_bytes.len() - coin_block.len() - suffix_bytes.len();
-            coin_block.extend(rng.sample_iter(dist).take(sample_len));
-
-            for x in 0..=255 {
-                for cb_idx in 0..coin_block.len() {
-                    hasher.update(&prefix_bytes).unwrap();
-                    hasher.update(&coin_block).unwrap();
-                    hasher.update(&suffix_bytes).unwrap();
-                    let h = hasher.finish()?;
-
-                    if hash_starts_n_zeroes(&h[..], num_zeros) {
-                        let coin = Coin {
-                            previous_coin : *previous_coin,
-                            num_zeros,
-                            blob : Vec::from(&coin_block[..])
-                        };
-
-                        match tdata.coin_schan.send(coin) {
-                            Ok(_) => {},
-                            Err(e) => return Err(Error::new(
-                                    format!("Miner Mpsc Send Failed: {:?}", e)))
-                        };
-
-                        return Ok(());
+            rng.fill_bytes(&mut message[i..BLOB_END]);
+
+            let hits = match self.simd {
+                Some(level) => {
+                    let mut words = [0u32; MESSAGE_WORDS];
+                    for (w, bytes) in words.iter_mut().zip(message.chunks(4)) {
+                        *w = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
                     }
+                    let midstate = md5::md5_midstate(&message[..MIDSTATE_BLOCKS * MD5_BLOCK_LEN]);
+
+                    simd_search(level, &words, &midstate, 0, CANDIDATES, num_zeros)
+                },
+                None => openssl_search(&message, 0, CANDIDATES, num_zeros)?,
+            };
 
-                    coin_block[cb_idx] += x;
+            for hit in hits {
+                let coin = Coin {
+                    previous_coin : previous_coin.clone(),
+                    num_zeros,
+                    blob : message_blob(&message, hit),
+                };
 
-                    counter += 1;
-                }
+                // Keep mining, the next coin comes as a command once this one is claimed
+                match tdata.coin_schan.send(coin) {
+                    Ok(_) => {},
+                    Err(e) => return Err(Error::new(
+                            format!("Miner Mpsc Send Failed: {:?}", e)))
+                };
             }
 
+            counter += CANDIDATES as u64;
+
             if last_report_timer.check_and_reset() {
                 tdata.stats_schan.send(Stats{
                     nhash: counter,
@@ -116,3 +219,38 @@ impl MinerFunction for CpuMinerFunction {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_search_ok() {
+        let mut message = [0u8; MESSAGE_LEN];
+        openssl::rand::rand_bytes(&mut message[..]).unwrap();
+
+        let mut words = [0u32; MESSAGE_WORDS];
+        for (w, bytes) in words.iter_mut().zip(message.chunks(4)) {
+            *w = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let midstate = md5::md5_midstate(&message[..MIDSTATE_BLOCKS * MD5_BLOCK_LEN]);
+
+        // Counters found hashing the whole message
+        let (start, count, num_zeros) = (1000, 4099, 2);
+        let expected : Vec<u32> = (start..start + count).filter(|c| {
+            let mut full = Vec::from(&message[..BLOB_INDEX]);
+            full.extend_from_slice(&message_blob(&message, *c));
+            full.extend_from_slice(&message[BLOB_END..]);
+            let h = openssl::hash::hash(MessageDigest::md5(), &full).unwrap();
+            hash_starts_n_zeroes(&h[..], num_zeros)
+        }).collect();
+        assert!(!expected.is_empty());
+
+        assert_eq!(openssl_search(&message, start, count, num_zeros).unwrap(), expected);
+
+        for level in [SimdLevel::Sse2, SimdLevel::Avx2].iter().filter(|l| l.supported()) {
+            assert_eq!(simd_search(*level, &words, &midstate, start, count, num_zeros), expected,
+                "{:?}", level);
+        }
+    }
+}
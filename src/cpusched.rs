@@ -0,0 +1,247 @@
+//! CPU Miner Thread Placement
+//!
+//! Pins miner threads to cores and lowers the priority of the CPU miners
+//! so the machine stays usable. Only supported on Linux.
+use crate::error::Error;
+use num_cpus;
+use std::fs;
+
+/// Cores past this don't fit in a cpu_set_t (CPU_SETSIZE)
+const MAX_CORES : usize = 1024;
+
+/// Scheduling priority of a thread
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Priority {
+    Normal,
+    /// Nice value, 19 is the lowest priority
+    Nice(i32),
+    /// SCHED_IDLE, only runs when nothing else wants the core
+    Idle,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// Cores and priority of a miner thread
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThreadSched {
+    /// Cores the thread may run on, any core if empty
+    pub cores : Vec<usize>,
+    pub priority : Priority,
+}
+
+impl ThreadSched {
+    /// Apply to the calling thread
+    #[cfg(target_os = "linux")]
+    pub fn apply(&self) -> Result<(), Error> {
+        let os_error = |what : String| Error::Msg(
+            format!("Failed to {}: {}", what, std::io::Error::last_os_error()));
+
+        unsafe {
+            if ! self.cores.is_empty() {
+                if let Some(core) = self.cores.iter().find(|&&c| c >= MAX_CORES) {
+                    return Err(Error::Msg(format!("Can't pin thread to core {}, the last is {}", core, MAX_CORES - 1)));
+                }
+
+                let mut set : libc::cpu_set_t = std::mem::zeroed();
+                for core in &self.cores {
+                    libc::CPU_SET(*core, &mut set);
+                }
+
+                if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+                    return Err(os_error(format!("pin thread to cores {:?}", self.cores)));
+                }
+            }
+
+            match self.priority {
+                Priority::Normal => {},
+                Priority::Nice(nice) => {
+                    // Nice values are per thread on Linux
+                    let tid = libc::syscall(libc::SYS_gettid) as libc::id_t;
+                    if libc::setpriority(libc::PRIO_PROCESS, tid, nice) != 0 {
+                        return Err(os_error(format!("set thread nice value to {}", nice)));
+                    }
+                },
+                Priority::Idle => {
+                    let param = libc::sched_param { sched_priority : 0 };
+                    if libc::sched_setscheduler(0, libc::SCHED_IDLE, &param) != 0 {
+                        return Err(os_error(String::from("set thread scheduler to SCHED_IDLE")));
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn apply(&self) -> Result<(), Error> {
+        if *self == ThreadSched::default() {
+            Ok(())
+        } else {
+            Err(Error::Msg("Thread pinning and priority are only supported on Linux".into()))
+        }
+    }
+}
+
+/// List of cores in the format of Linux cpulist files, E.g. 0-3,8,10-11
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuList(pub Vec<usize>);
+
+impl std::str::FromStr for CpuList {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        let mut cores = Vec::new();
+
+        for part in s.trim().split(',').map(|p| p.trim()).filter(|p| ! p.is_empty()) {
+            let parse = |c : &str| match c.trim().parse::<usize>() {
+                Ok(core) if core >= MAX_CORES => Err(format!("Bad core \"{}\": the last is {}", c, MAX_CORES - 1)),
+                Ok(core) => Ok(core),
+                Err(e) => Err(format!("Bad core \"{}\": {}", c, e)),
+            };
+
+            let mut ends = part.splitn(2, '-');
+            let first = parse(ends.next().unwrap())?;
+            let last = match ends.next() {
+                Some(last) => parse(last)?,
+                None => first,
+            };
+
+            if last < first {
+                return Err(format!("Bad core range \"{}\"", part));
+            }
+
+            cores.extend(first..=last);
+        }
+
+        if cores.is_empty() {
+            return Err(format!("No cores in \"{}\"", s));
+        }
+
+        Ok(CpuList(cores))
+    }
+}
+
+/// Cores of a NUMA node
+pub fn numa_node_cores(node : usize) -> Result<Vec<usize>, Error> {
+    let path = format!("/sys/devices/system/node/node{}/cpulist", node);
+    let list = fs::read_to_string(&path).map_err(|e| Error::Msg(
+            format!("Failed to read the cores of NUMA node {} from {}: {}", node, path, e)))?;
+
+    list.parse::<CpuList>().map(|l| l.0).map_err(Error::Msg)
+}
+
+/// Where the CPU and OpenCL miner threads run
+#[derive(Clone, Debug, Default)]
+pub struct Placement {
+    cpu_cores : Vec<usize>,
+    ocl_cores : Vec<usize>,
+    cpu_priority : Priority,
+    reserve_ocl_core : bool,
+}
+
+impl Placement {
+    /// Threads aren't pinned if no cores are given. With reserve_ocl_core one core
+    /// is kept for the OpenCL miners feeding their queues, the last given core if pinned.
+    pub fn new(mut cores : Vec<usize>, cpu_priority : Priority, reserve_ocl_core : bool) -> Self {
+        cores.sort();
+        cores.dedup();
+
+        let ocl_cores = if reserve_ocl_core && cores.len() > 1 {
+            vec![cores.pop().unwrap()]
+        } else {
+            Vec::new()
+        };
+
+        Placement {
+            cpu_cores : cores,
+            ocl_cores,
+            cpu_priority,
+            reserve_ocl_core,
+        }
+    }
+
+    /// Number of CPU miners when not given, one for each core not reserved
+    pub fn default_ncpu(&self) -> usize {
+        if self.cpu_cores.is_empty() {
+            num_cpus::get().saturating_sub(self.reserve_ocl_core as usize)
+        } else {
+            self.cpu_cores.len()
+        }
+    }
+
+    /// The i'th CPU miner gets its own core, cores are shared if there are more miners than cores
+    pub fn cpu_miner(&self, i : usize) -> ThreadSched {
+        ThreadSched {
+            cores : self.cpu_cores.iter().cycle().nth(i).cloned().into_iter().collect(),
+            priority : self.cpu_priority,
+        }
+    }
+
+    /// OpenCL miners keep the normal priority so the GPUs don't wait on the CPU miners
+    pub fn ocl_miner(&self) -> ThreadSched {
+        ThreadSched {
+            cores : self.ocl_cores.clone(),
+            priority : Priority::Normal,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cpu_list_ok() {
+        assert_eq!("0-3,8,10-11\n".parse::<CpuList>(), Ok(CpuList(vec![0, 1, 2, 3, 8, 10, 11])));
+        assert_eq!("5".parse::<CpuList>(), Ok(CpuList(vec![5])));
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("a".parse::<CpuList>().is_err());
+        assert!("".parse::<CpuList>().is_err());
+        assert!("5000".parse::<CpuList>().is_err());
+        assert!("0-99999999999".parse::<CpuList>().is_err());
+        assert_eq!("1023".parse::<CpuList>(), Ok(CpuList(vec![1023])));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_thread_bad_core() {
+        assert_eq!(MAX_CORES, libc::CPU_SETSIZE as usize);
+
+        let pinned = std::thread::spawn(|| {
+            ThreadSched { cores : vec![MAX_CORES], priority : Priority::Normal }.apply()
+        }).join().unwrap();
+
+        assert!(pinned.is_err());
+    }
+
+    #[test]
+    fn test_placement_ok() {
+        let placement = Placement::new(vec![3, 1, 2, 0], Priority::Idle, true);
+
+        assert_eq!(placement.default_ncpu(), 3);
+        assert_eq!(placement.cpu_miner(0), ThreadSched { cores : vec![0], priority : Priority::Idle });
+        assert_eq!(placement.cpu_miner(4), ThreadSched { cores : vec![1], priority : Priority::Idle });
+        assert_eq!(placement.ocl_miner(), ThreadSched { cores : vec![3], priority : Priority::Normal });
+
+        // Not pinned
+        let placement = Placement::new(Vec::new(), Priority::Nice(10), true);
+        assert_eq!(placement.cpu_miner(2), ThreadSched { cores : Vec::new(), priority : Priority::Nice(10) });
+        assert_eq!(placement.ocl_miner(), ThreadSched::default());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_thread_nice_ok() {
+        let nice = std::thread::spawn(|| {
+            ThreadSched { cores : Vec::new(), priority : Priority::Nice(19) }.apply().unwrap();
+            unsafe { libc::getpriority(libc::PRIO_PROCESS, libc::syscall(libc::SYS_gettid) as libc::id_t) }
+        }).join().unwrap();
+
+        assert_eq!(nice, 19);
+    }
+}
@@ -3,6 +3,7 @@ use hex::FromHexError;
 use openssl::error::ErrorStack;
 use std::io;
 use ocl;
+use serde_json;
 
 #[derive(Debug)]
 pub enum Error {
@@ -11,8 +12,10 @@ pub enum Error {
     OpenCL(ocl::Error),
     Hex(FromHexError),
     Io(io::Error),
+    Json(serde_json::Error),
     BadCoin(String),
     ServerBusy,
+    Timeout,
     AllRequestsFailed(String),
     Msg(String)
 }
@@ -53,3 +56,9 @@ impl From<io::Error> for Error {
     }
 }
 
+
+impl From<serde_json::Error> for Error {
+    fn from(e : serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
@@ -0,0 +1,326 @@
+//! Fake Tracker
+//!
+//! Simulates cpen442coin.ece.ubc.ca locally so the miner can be run
+//! without the real contest.
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::error::Error;
+use crate::cpen442coin::{self, CoinTracker, COIN_PREFIX_STR, MD5_HASH_HEX_LEN,
+    LastCoinResp, DifficultyResp, ClaimCoinResp};
+use crate::faults::{FaultInjector, TrackerOp};
+use openssl::hash;
+use reqwest::StatusCode;
+use rand::{Rng, RngCore, rngs::OsRng};
+use crate::util::*;
+
+/// Options for the fake tracker
+pub struct FakeTrackerOpts {
+    /// Number of leading zeroes a coin needs
+    pub difficulty : u64,
+    /// Coin to start mining on, a random coin is used if not given
+    pub start_coin : Option<String>,
+    /// Average number of coins per hour claimed by a simulated rival miner
+    pub rival_coins_per_hour : f64,
+    /// Difficulty changes after the tracker starts
+    pub difficulty_schedule : Option<DifficultySchedule>,
+}
+
+/// Difficulty changes over time for the fake tracker
+///
+/// Parsed from a comma separated list of `seconds:difficulty` pairs,
+/// e.g. `300:10,600:11` raises the difficulty to 10 after 5 minutes
+/// then to 11 after 10 minutes.
+#[derive(Debug)]
+pub struct DifficultySchedule(Vec<(Duration, u64)>);
+
+impl std::str::FromStr for DifficultySchedule {
+    type Err = String;
+
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        let mut schedule = Vec::new();
+
+        for entry in s.split(',') {
+            let mut parts = entry.trim().splitn(2, ':');
+            let secs = parts.next().unwrap_or("");
+            let num_zeros = parts.next()
+                .ok_or_else(|| format!("Expected seconds:difficulty, got \"{}\"", entry))?;
+
+            let secs : u64 = secs.trim().parse()
+                .map_err(|e| format!("Bad time \"{}\": {}", secs, e))?;
+            let num_zeros : u64 = num_zeros.trim().parse()
+                .map_err(|e| format!("Bad difficulty \"{}\": {}", num_zeros, e))?;
+
+            schedule.push((Duration::from_secs(secs), num_zeros));
+        }
+
+        schedule.sort_by_key(|(t, _)| *t);
+
+        Ok(DifficultySchedule(schedule))
+    }
+}
+
+pub struct FakeTracker {
+    miner_id : String,
+    last_coin : String,
+    difficulty : u64,
+    rival_rate : f64,
+    rival_next : Option<Instant>,
+    start : Instant,
+    difficulty_schedule : Vec<(Duration, u64)>,
+    faults : FaultInjector,
+}
+
+impl FakeTracker {
+    pub fn new(miner_id: String, opts : FakeTrackerOpts,
+        faults : FaultInjector) -> Result<FakeTracker, Error> {
+        if opts.difficulty < 8 || opts.difficulty >= 16 {
+            return Err(Error::Msg("Fake difficulty should be between 8 and 15".into()));
+        }
+
+        let difficulty_schedule = opts.difficulty_schedule
+            .map(|s| s.0)
+            .unwrap_or_default();
+
+        for (_, num_zeros) in &difficulty_schedule {
+            if *num_zeros < 8 || *num_zeros >= 16 {
+                return Err(Error::Msg(format!(
+                            "Fake difficulty schedule has difficulty {}, it should be between 8 and 15",
+                            num_zeros)));
+            }
+        }
+
+        if opts.rival_coins_per_hour < 0.0 {
+            return Err(Error::Msg("Fake rival coin rate should not be negative".into()));
+        }
+
+        let last_coin = match opts.start_coin {
+            Some(coin) => {
+                if coin.len() != MD5_HASH_HEX_LEN {
+                    return Err(Error::Msg(format!("Fake coin should be of length {}",
+                                MD5_HASH_HEX_LEN)));
+                }
+                hex::decode(&coin)?;
+
+                coin
+            },
+            None => Self::random_coin(&miner_id),
+        };
+
+        let mut rival_rate = 0.0;
+        let mut rival_next = None;
+        if opts.rival_coins_per_hour > 0.0 {
+            rival_rate = opts.rival_coins_per_hour / 3600.0;
+            rival_next = Some(Instant::now() + Self::rival_interval(rival_rate));
+        }
+
+        Ok(FakeTracker {
+            miner_id,
+            last_coin,
+            difficulty : opts.difficulty,
+            rival_rate,
+            rival_next,
+            start : Instant::now(),
+            difficulty_schedule,
+            faults,
+        })
+    }
+
+    fn random_coin(seed : &str) -> String {
+        // Generate a random coin
+        let mut hasher = hash::Hasher::new(hash::MessageDigest::md5()).unwrap();
+        hasher.update(seed.as_bytes()).unwrap();
+        hasher.update(&OsRng.next_u32().to_le_bytes()).unwrap();
+        let mut fake_coin = hasher.finish().unwrap();
+        fake_coin[0] = 0;
+        fake_coin[1] = 0;
+        fake_coin[2] = 0;
+        fake_coin[3] = 0;
+
+        hex::encode(&fake_coin[..])
+    }
+
+    /// Time until the next rival claim, the claims are a poisson process
+    /// so the time between them is exponentially distributed.
+    fn rival_interval(rate_per_sec : f64) -> Duration {
+        let u : f64 = OsRng.gen();
+
+        Duration::from_secs_f64(-(1.0 - u).ln() / rate_per_sec)
+    }
+
+    /// Let the simulated rival claim any coins it has found since the last check
+    fn rival_claims(&mut self) {
+        if let Some(mut next) = self.rival_next {
+            let mut claimed = false;
+
+            while next <= Instant::now() {
+                next += Self::rival_interval(self.rival_rate);
+                claimed = true;
+            }
+
+            if claimed {
+                let coin = Self::random_coin("FAKE RIVAL");
+                println!("\nFake Rival Claimed Coin: {}", coin);
+                self.last_coin = coin;
+            }
+
+            self.rival_next = Some(next);
+        }
+    }
+
+    /// Seconds since the epoch, as sent by the tracker
+    fn time_stamp() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+
+    /// Apply any difficulty changes from the schedule that are due
+    fn difficulty_changes(&mut self) {
+        let elapsed = self.start.elapsed();
+        let mut due = 0;
+
+        for (i, (t, _)) in self.difficulty_schedule.iter().enumerate() {
+            if *t <= elapsed {
+                due = i + 1;
+            }
+        }
+
+        for (_, num_zeros) in self.difficulty_schedule.drain(..due) {
+            if self.difficulty != num_zeros {
+                println!("\nFake Tracker Difficulty Changed: {}", num_zeros);
+                self.difficulty = num_zeros;
+            }
+        }
+    }
+}
+
+impl CoinTracker for FakeTracker {
+    fn id(&self) -> &str {
+        &self.miner_id
+    }
+
+    // Responses go through the same JSON parsing as the real tracker's
+
+    fn last_coin(&mut self) -> Result<String, Error> {
+        self.rival_claims();
+        let injected = self.faults.inject(TrackerOp::LastCoin)?;
+
+        let body = serde_json::to_string(&LastCoinResp {
+            coin_id : self.last_coin.clone(),
+            id_of_miner : self.miner_id.clone(),
+            time_stamp : Self::time_stamp(),
+        })?;
+
+        cpen442coin::parse_last_coin(StatusCode::OK, &injected.body(body))
+    }
+
+    fn difficulty(&mut self) -> Result<u64, Error> {
+        self.difficulty_changes();
+        let injected = self.faults.inject(TrackerOp::Difficulty)?;
+
+        let body = serde_json::to_string(&DifficultyResp {
+            number_of_leading_zeros : self.difficulty,
+            time_stamp : Self::time_stamp(),
+        })?;
+
+        cpen442coin::parse_difficulty(StatusCode::OK, &injected.body(body))
+    }
+
+    fn claim(&mut self,
+        blob: Vec<u8>,
+        previous_coin: String,
+        hash: &str) -> Result<(), Error> {
+
+        self.rival_claims();
+        self.difficulty_changes();
+        let injected = self.faults.inject(TrackerOp::ClaimCoin)?;
+
+        if self.last_coin != previous_coin {
+            return Err(Error::new("Previous coin does not match!".into()));
+        }
+
+        let mut hasher = hash::Hasher::new(hash::MessageDigest::md5()).unwrap();
+        hasher.update(COIN_PREFIX_STR.as_bytes()).unwrap();
+        hasher.update(self.last_coin.as_bytes()).unwrap();
+        hasher.update(&blob).unwrap();
+        hasher.update(self.miner_id.as_bytes()).unwrap();
+        let h = hasher.finish().unwrap();
+        let h_hex = hex::encode(&h[..]);
+        if hex_starts_n_zeroes(hash, self.difficulty) {
+            if hash != h_hex {
+                return Err(Error::new(
+                        format!("Calculated hash {} does not match given hash {}!",
+                            h_hex, hash)
+                ));
+            }
+
+            self.last_coin = h_hex;
+
+            let body = serde_json::to_string(&ClaimCoinResp::Success {
+                success : "Coin claimed".into(),
+            })?;
+
+            cpen442coin::parse_claim(StatusCode::OK, &injected.body(body))
+        } else {
+            let mut msg = Vec::new();
+            msg.extend_from_slice(COIN_PREFIX_STR.as_bytes());
+            msg.extend_from_slice(self.last_coin.as_bytes());
+            msg.extend_from_slice(&blob);
+            msg.extend_from_slice(self.miner_id.as_bytes());
+            Err(Error::new(format!("Invalid Coin Hash: {} Coin: {}",
+                        h_hex, hex::encode(msg))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::faults::FaultRates;
+
+    #[test]
+    fn test_difficulty_schedule_parse_ok() {
+        let schedule : DifficultySchedule = "600:11, 0:9,300:10".parse().unwrap();
+
+        assert_eq!(schedule.0, vec![
+            (Duration::from_secs(0), 9),
+            (Duration::from_secs(300), 10),
+            (Duration::from_secs(600), 11),
+        ]);
+    }
+
+    fn tracker(rates : FaultRates) -> FakeTracker {
+        FakeTracker::new("0123456789abcdef0123456789abcdef".into(), FakeTrackerOpts {
+            difficulty : 9,
+            start_coin : None,
+            rival_coins_per_hour : 0.0,
+            difficulty_schedule : None,
+        }, FaultInjector::new(rates).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_faults_ok() {
+        let no_faults = FaultRates { server_busy : 0.0, bad_coin : 0.0, timeout : 0.0, malformed : 0.0 };
+
+        let mut fake = tracker(FaultRates { ..no_faults });
+        assert_eq!(fake.difficulty().unwrap(), 9);
+        assert_eq!(fake.last_coin().unwrap().len(), MD5_HASH_HEX_LEN);
+
+        // Cut short bodies fail in the JSON parsing
+        let mut fake = tracker(FaultRates { malformed : 1.0, ..no_faults });
+        assert!(match fake.last_coin() { Err(Error::Json(_)) => true, _ => false });
+        assert!(match fake.difficulty() { Err(Error::Json(_)) => true, _ => false });
+
+        // Timeouts fail at once without blocking
+        let mut fake = tracker(FaultRates { timeout : 1.0, ..no_faults });
+        let start = Instant::now();
+        for _ in 0..10 {
+            assert!(match fake.last_coin() { Err(Error::Timeout) => true, _ => false });
+        }
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_difficulty_schedule_parse_bad() {
+        assert!("300".parse::<DifficultySchedule>().is_err());
+        assert!("a:10".parse::<DifficultySchedule>().is_err());
+        assert!("300:b".parse::<DifficultySchedule>().is_err());
+    }
+}
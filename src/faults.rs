@@ -0,0 +1,125 @@
+//! Fault Injection
+//!
+//! Makes tracker requests fail on purpose so the retry
+//! and backoff logic can be tested without the real tracker misbehaving.
+use crate::error::Error;
+use rand::{Rng, rngs::OsRng};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long the tracker stays unreachable after an injected timeout,
+/// same as the proxy client timeout
+const FAULT_TIMEOUT : Duration = Duration::from_secs(4);
+
+#[derive(Clone, Copy, Debug)]
+pub enum TrackerOp {
+    LastCoin,
+    Difficulty,
+    ClaimCoin,
+}
+
+/// Chance of each fault happening per request [0 to 1]
+pub struct FaultRates {
+    pub server_busy : f64,
+    pub bad_coin : f64,
+    pub timeout : f64,
+    pub malformed : f64,
+}
+
+/// What happens to a request that wasn't failed outright
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Injected {
+    Nothing,
+    /// The response body gets cut short
+    Malformed,
+}
+
+impl Injected {
+    /// The response body the request should parse
+    pub fn body(self, body : String) -> String {
+        match self {
+            Injected::Nothing => body,
+            // Fail the same way a truncated response would
+            Injected::Malformed => body.chars().take(body.chars().count() / 2).collect(),
+        }
+    }
+}
+
+pub struct FaultInjector {
+    rates : FaultRates,
+    /// Until when an injected timeout leaves the tracker unreachable
+    timed_out_until : Mutex<Option<Instant>>,
+}
+
+impl FaultInjector {
+    pub fn new(rates : FaultRates) -> Result<Self, Error> {
+        let all = [rates.server_busy, rates.bad_coin, rates.timeout, rates.malformed];
+
+        if all.iter().any(|r| *r < 0.0 || *r > 1.0) {
+            return Err(Error::Msg("Fault rates should be between 0 and 1".into()));
+        }
+
+        if all.iter().sum::<f64>() > 1.0 {
+            return Err(Error::Msg("Fault rates should not add up to more than 1".into()));
+        }
+
+        Ok(Self {
+            rates,
+            timed_out_until : Mutex::new(None),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.rates.server_busy > 0.0 || self.rates.bad_coin > 0.0 ||
+            self.rates.timeout > 0.0 || self.rates.malformed > 0.0
+    }
+
+    /// Roll for a fault on a request, returns the error the request should fail with.
+    ///
+    /// Timeouts don't block the caller, the request fails at once and so does
+    /// every other request until FAULT_TIMEOUT has passed, like an unreachable tracker.
+    pub fn inject(&self, op : TrackerOp) -> Result<Injected, Error> {
+        if ! self.enabled() {
+            return Ok(Injected::Nothing);
+        }
+
+        {
+            let mut timed_out_until = self.timed_out_until.lock().unwrap();
+
+            match *timed_out_until {
+                Some(until) if Instant::now() < until => return Err(Error::Timeout),
+                Some(_) => *timed_out_until = None,
+                None => {},
+            }
+        }
+
+        let mut r : f64 = OsRng.gen();
+
+        r -= self.rates.server_busy;
+        if r < 0.0 {
+            return Err(Error::ServerBusy);
+        }
+
+        // Only claims can be rejected, the other requests get a 400 as busy
+        r -= self.rates.bad_coin;
+        if r < 0.0 {
+            return match op {
+                TrackerOp::ClaimCoin => Err(Error::BadCoin("Injected Fault".into())),
+                _ => Err(Error::ServerBusy),
+            };
+        }
+
+        r -= self.rates.timeout;
+        if r < 0.0 {
+            *self.timed_out_until.lock().unwrap() = Some(Instant::now() + FAULT_TIMEOUT);
+            return Err(Error::Timeout);
+        }
+
+        r -= self.rates.malformed;
+        if r < 0.0 {
+            return Ok(Injected::Malformed);
+        }
+
+        Ok(Injected::Nothing)
+    }
+}
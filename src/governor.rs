@@ -0,0 +1,382 @@
+//! CPU Miner Governor
+//!
+//! Runs fewer CPU miners while other programs keep the CPU busy or the
+//! CPU temperature is above a limit, and more again once it drops.
+use crate::{error::Error, util::Timer};
+use std::fs;
+use std::time::Duration;
+
+const STAT_PATH : &str = "/proc/stat";
+const SELF_STAT_PATH : &str = "/proc/self/stat";
+const THERMAL_PATH : &str = "/sys/class/thermal";
+/// How often the load and temperature are checked, the load is the CPU use over this time
+const CHECK_INTERVAL : Duration = Duration::from_secs(5);
+/// How many cores under the limit the load needs to be before another miner is started
+const LOAD_HYSTERESIS : f64 = 0.5;
+/// How far under the limit the temperature needs to be before another miner is started
+const TEMP_HYSTERESIS : f64 = 5.0;
+/// Checks in a row the temperature needs to stay that far under before another miner is
+/// started, the temperature takes a while to follow a change in miners
+const TEMP_SETTLE_CHECKS : u32 = 6;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GovernorOpts {
+    /// Busy cores, each CPU miner counted as one plus what other programs use
+    pub max_load : Option<f64>,
+    /// Hottest thermal zone in degrees Celsius
+    pub max_temp : Option<f64>,
+}
+
+/// What the governor decides on, None where it couldn't be read
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Reading {
+    /// Cores busy with programs other than this one
+    other_load : Option<f64>,
+    temp : Option<f64>,
+}
+
+/// Where readings come from, the system or a simulated one in tests
+trait Sensors {
+    fn read(&mut self, opts : &GovernorOpts) -> Reading;
+}
+
+/// CPU time counters in clock ticks, summed over all cores
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct CpuTimes {
+    busy : u64,
+    total : u64,
+    cores : usize,
+    /// Used by this process
+    own : u64,
+}
+
+impl CpuTimes {
+    /// Cores other programs kept busy between the two readings,
+    /// None if no time passed
+    fn other_load_since(&self, last : &CpuTimes) -> Option<f64> {
+        let total = self.total.checked_sub(last.total)?;
+        if total == 0 {
+            return None;
+        }
+
+        let busy = self.busy.saturating_sub(last.busy);
+        let own = self.own.saturating_sub(last.own);
+
+        Some(busy.saturating_sub(own) as f64 / total as f64 * self.cores as f64)
+    }
+}
+
+/// Reads /proc and /sys, the load is the CPU use since the last read
+struct SystemSensors {
+    last_times : Option<CpuTimes>,
+}
+
+impl Sensors for SystemSensors {
+    fn read(&mut self, opts : &GovernorOpts) -> Reading {
+        let other_load = match opts.max_load {
+            Some(_) => {
+                let times = read_cpu_times().ok();
+                let load = match (times, self.last_times) {
+                    (Some(times), Some(last)) => times.other_load_since(&last),
+                    _ => None,
+                };
+                self.last_times = times;
+                load
+            },
+            None => None,
+        };
+
+        Reading {
+            other_load,
+            temp : opts.max_temp.and_then(|_| read_temp().ok()),
+        }
+    }
+}
+
+/// Decides how many CPU miners run
+pub struct Governor {
+    opts : GovernorOpts,
+    max_miners : usize,
+    active : usize,
+    check_timer : Timer,
+    sensors : Box<dyn Sensors>,
+    /// Checks in a row the temperature has been well under its limit
+    cool_checks : u32,
+}
+
+impl Governor {
+    /// Starts with all max_miners running, fails if a limit is given
+    /// that can't be read on this system
+    pub fn new(opts : GovernorOpts, max_miners : usize) -> Result<Self, Error> {
+        if opts.max_load.is_some() {
+            read_cpu_times()?;
+        }
+
+        if opts.max_temp.is_some() {
+            read_temp()?;
+        }
+
+        let mut sensors = SystemSensors { last_times : None };
+        // The first load is measured from here
+        sensors.read(&opts);
+
+        Ok(Self::with_sensors(opts, max_miners, Box::new(sensors), CHECK_INTERVAL))
+    }
+
+    fn with_sensors(opts : GovernorOpts, max_miners : usize,
+                    sensors : Box<dyn Sensors>, interval : Duration) -> Self {
+        Governor {
+            opts,
+            max_miners,
+            active : max_miners,
+            check_timer : Timer::new(interval),
+            sensors,
+            cool_checks : 0,
+        }
+    }
+
+    /// Number of CPU miners that should run, rechecked every CHECK_INTERVAL
+    pub fn update(&mut self) -> usize {
+        if self.check_timer.check_and_reset() {
+            let reading = self.sensors.read(&self.opts);
+            self.active = self.next_active(reading);
+        }
+
+        self.active
+    }
+
+    /// Straight down to the miners that fit beside the other programs' load,
+    /// one down per check while too hot. One more only once there is room for
+    /// it under every limit. A limit that couldn't be read keeps the miners as they are.
+    fn next_active(&mut self, reading : Reading) -> usize {
+        let mut most = self.max_miners;
+        let mut raise = self.active < self.max_miners;
+
+        match (self.opts.max_load, reading.other_load) {
+            (Some(max), Some(other)) => {
+                // The miners' own load isn't in other, so this doesn't feed back on itself
+                let fit = |margin : f64| (max - margin - other).floor().max(0.0) as usize;
+                most = most.min(fit(0.0));
+                raise &= fit(LOAD_HYSTERESIS) > self.active;
+            },
+            (Some(_), None) => raise = false,
+            _ => {},
+        }
+
+        match (self.opts.max_temp, reading.temp) {
+            (Some(max), Some(temp)) => {
+                if temp > max {
+                    most = most.min(self.active.saturating_sub(1));
+                }
+
+                if temp < max - TEMP_HYSTERESIS {
+                    self.cool_checks += 1;
+                } else {
+                    self.cool_checks = 0;
+                }
+
+                raise &= self.cool_checks >= TEMP_SETTLE_CHECKS;
+            },
+            (Some(_), None) => raise = false,
+            _ => {},
+        }
+
+        if self.active > most {
+            most
+        } else if raise {
+            // The temperature settles again before the next one
+            self.cool_checks = 0;
+            self.active + 1
+        } else {
+            self.active
+        }
+    }
+}
+
+/// Busy and total ticks and the number of cores from /proc/stat
+fn parse_cpu_stat(s : &str) -> Result<(u64, u64, usize), Error> {
+    let bad = || Error::Msg(String::from("Bad /proc/stat"));
+
+    let ticks = s.lines().next()
+        .filter(|l| l.starts_with("cpu "))
+        .ok_or_else(bad)?
+        .split_whitespace().skip(1)
+        .map(|t| t.parse::<u64>().map_err(|_| bad()))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if ticks.len() < 8 {
+        return Err(bad());
+    }
+
+    // user nice system idle iowait irq softirq steal, guest time is already in user
+    let idle = ticks[3] + ticks[4];
+    let busy = ticks[0] + ticks[1] + ticks[2] + ticks[5] + ticks[6] + ticks[7];
+
+    let cores = s.lines()
+        .filter(|l| l.starts_with("cpu") && l.as_bytes().get(3).map_or(false, u8::is_ascii_digit))
+        .count();
+
+    Ok((busy, busy + idle, cores.max(1)))
+}
+
+/// User and system ticks of a process from /proc/<pid>/stat
+fn parse_self_stat(s : &str) -> Result<u64, Error> {
+    // The name in brackets may contain spaces, fields are counted after it from state (3)
+    let fields : Vec<_> = s.rsplit(')').next().unwrap_or("").split_whitespace().collect();
+
+    match (fields.get(11).map(|t| t.parse::<u64>()), fields.get(12).map(|t| t.parse::<u64>())) {
+        (Some(Ok(utime)), Some(Ok(stime))) => Ok(utime + stime),
+        _ => Err(Error::Msg(String::from("Bad /proc/self/stat"))),
+    }
+}
+
+fn read_cpu_times() -> Result<CpuTimes, Error> {
+    let (busy, total, cores) = parse_cpu_stat(&fs::read_to_string(STAT_PATH)?)?;
+    let own = parse_self_stat(&fs::read_to_string(SELF_STAT_PATH)?)?;
+
+    Ok(CpuTimes { busy, total, cores, own })
+}
+
+/// Hottest thermal zone in degrees Celsius
+fn read_temp() -> Result<f64, Error> {
+    let mut hottest : Option<f64> = None;
+
+    for entry in fs::read_dir(THERMAL_PATH)? {
+        let path = entry?.path();
+
+        if ! path.file_name().map(|n| n.to_string_lossy().starts_with("thermal_zone")).unwrap_or(false) {
+            continue;
+        }
+
+        // Millidegrees, zones that can't be read are skipped
+        if let Ok(Ok(millis)) = fs::read_to_string(path.join("temp")).map(|t| t.trim().parse::<f64>()) {
+            let temp = millis / 1000.0;
+            hottest = Some(hottest.map_or(temp, |h| h.max(temp)));
+        }
+    }
+
+    hottest.ok_or_else(|| Error::Msg(format!("No temperatures found in {}", THERMAL_PATH)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::thread;
+
+    /// Other programs use `other` cores, each miner heats the CPU by 5 degrees
+    /// over 40 and the temperature moves a third of the way there every check
+    struct SimSensors {
+        miners : Rc<Cell<usize>>,
+        other : Rc<Cell<f64>>,
+        temp : f64,
+    }
+
+    impl Sensors for SimSensors {
+        fn read(&mut self, _opts : &GovernorOpts) -> Reading {
+            self.temp += (40.0 + 5.0 * self.miners.get() as f64 - self.temp) / 3.0;
+            Reading { other_load : Some(self.other.get()), temp : Some(self.temp) }
+        }
+    }
+
+    fn sim_governor(opts : GovernorOpts, max_miners : usize, other : f64)
+        -> (Governor, Rc<Cell<usize>>, Rc<Cell<f64>>) {
+        let miners = Rc::new(Cell::new(max_miners));
+        let other = Rc::new(Cell::new(other));
+        let sensors = SimSensors { miners : miners.clone(), other : other.clone(), temp : 40.0 };
+
+        (Governor::with_sensors(opts, max_miners, Box::new(sensors), Duration::from_secs(0)), miners, other)
+    }
+
+    /// Runs the given update rounds, returns the miners after each
+    fn run(governor : &mut Governor, miners : &Rc<Cell<usize>>, rounds : usize) -> Vec<usize> {
+        (0..rounds).map(|_| {
+            // Let the zero interval timer expire
+            thread::sleep(Duration::from_millis(1));
+            miners.set(governor.update());
+            miners.get()
+        }).collect()
+    }
+
+    #[test]
+    fn test_governor_load_ok() {
+        let opts = GovernorOpts { max_load : Some(8.0), max_temp : None };
+        let (mut governor, miners, other) = sim_governor(opts, 8, 0.0);
+
+        assert!(run(&mut governor, &miners, 5).iter().all(|&n| n == 8));
+
+        // Straight down to what fits and stays there
+        other.set(4.5);
+        assert!(run(&mut governor, &miners, 20).iter().all(|&n| n == 3));
+
+        // Load right under the limit doesn't start another
+        other.set(4.7);
+        assert!(run(&mut governor, &miners, 5).iter().all(|&n| n == 3));
+
+        // Back up one at a time while half a core stays spare, never over
+        other.set(0.0);
+        assert_eq!(run(&mut governor, &miners, 8), vec![4, 5, 6, 7, 7, 7, 7, 7]);
+        other.set(0.4);
+        assert!(run(&mut governor, &miners, 5).iter().all(|&n| n == 7));
+
+        // More load than the limit stops all of them
+        other.set(9.0);
+        assert_eq!(run(&mut governor, &miners, 2), vec![0, 0]);
+    }
+
+    #[test]
+    fn test_governor_temp_ok() {
+        // 8 miners settle at 80 degrees, over the limit of 70
+        let opts = GovernorOpts { max_load : None, max_temp : Some(70.0) };
+        let (mut governor, miners, _) = sim_governor(opts, 8, 0.0);
+
+        let rounds = run(&mut governor, &miners, 200);
+
+        // Settles a miner or two under the 6 that fit without going near 0
+        assert!(rounds.iter().all(|&n| n >= 4));
+        let settled = &rounds[50..];
+        assert!(settled.iter().all(|&n| n >= 5 && n <= 6));
+        // Rarely changes once settled
+        assert!(settled.windows(2).filter(|w| w[0] != w[1]).count() <= settled.len() / 5);
+    }
+
+    #[test]
+    fn test_governor_unread_ok() {
+        let opts = GovernorOpts { max_load : Some(8.0), max_temp : Some(70.0) };
+        let (mut governor, _, _) = sim_governor(opts, 8, 0.0);
+        governor.active = 2;
+
+        // Unread limits keep the miners, only the limits given matter
+        for _ in 0..10 {
+            assert_eq!(governor.next_active(Reading { other_load : Some(0.0), temp : None }), 2);
+            assert_eq!(governor.next_active(Reading { other_load : None, temp : Some(40.0) }), 2);
+        }
+        governor.opts.max_temp = None;
+        assert_eq!(governor.next_active(Reading { other_load : Some(0.0), temp : None }), 3);
+    }
+
+    #[test]
+    fn test_other_load_ok() {
+        let last = CpuTimes { busy : 1000, total : 4000, cores : 4, own : 300 };
+
+        // 4 cores for 100 ticks, 300 busy of which 100 were this process
+        let now = CpuTimes { busy : 1300, total : 4400, cores : 4, own : 400 };
+        assert_eq!(now.other_load_since(&last), Some(2.0));
+        assert_eq!(last.other_load_since(&last), None);
+    }
+
+    #[test]
+    fn test_parse_stat_ok() {
+        let stat = "cpu  93369 596 10231 437861 463 0 10 538 0 0\n\
+                    cpu0 46000 296 5000 218000 200 0 5 269 0 0\n\
+                    cpu1 47369 300 5231 219861 263 0 5 269 0 0\n\
+                    intr 529282 0 0\n";
+        assert_eq!(parse_cpu_stat(stat).unwrap(), (104744, 104744 + 438324, 2));
+        assert!(parse_cpu_stat("intr 1 2\n").is_err());
+
+        let self_stat = "5605 (a (b) c) R 5600 5605 5600 0 -1 4194304 80 0 0 0 12 3 0 0 20 0 1 0";
+        assert_eq!(parse_self_stat(self_stat).unwrap(), 15);
+        assert!(parse_self_stat("5605 (cat) R 1").is_err());
+    }
+}
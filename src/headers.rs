@@ -0,0 +1,139 @@
+//! Request Headers
+//!
+//! Every tracker request goes through a `HeaderPolicy` so all
+//! endpoints send the same kind of headers.
+use crate::error::Error;
+use rand::{Rng, RngCore, rngs::OsRng, seq::SliceRandom};
+use reqwest::RequestBuilder;
+use reqwest::header::USER_AGENT;
+use std::thread;
+use std::time::Duration;
+
+/// Replaced with a random number in user agents
+const RAND_PLACEHOLDER : &str = "{rand}";
+
+pub const DEFAULT_USER_AGENT : &str = "CPEN442 Miner {rand}";
+
+pub struct HeaderOpts {
+    /// User agents to pick from at random for each request
+    pub user_agents : Vec<String>,
+    /// Send a random X-Forwarded-For header
+    pub forwarded_for : bool,
+    /// Wait up to this long before each request
+    pub jitter : Duration,
+}
+
+pub struct HeaderPolicy {
+    opts : HeaderOpts,
+}
+
+impl HeaderPolicy {
+    pub fn new(mut opts : HeaderOpts) -> Result<Self, Error> {
+        if opts.user_agents.iter().any(|ua| ua.trim().is_empty()) {
+            return Err(Error::Msg("User agents should not be empty".into()));
+        }
+
+        if opts.user_agents.is_empty() {
+            opts.user_agents.push(DEFAULT_USER_AGENT.into());
+        }
+
+        Ok(Self { opts })
+    }
+
+    /// Add the headers to a request, waiting for the jitter first
+    pub fn apply(&self, req : RequestBuilder) -> RequestBuilder {
+        let jitter_ms = self.opts.jitter.as_millis() as u64;
+        if jitter_ms > 0 {
+            thread::sleep(Duration::from_millis(OsRng.gen_range(0, jitter_ms + 1)));
+        }
+
+        let user_agent = self.opts.user_agents.choose(&mut OsRng).unwrap()
+            .replace(RAND_PLACEHOLDER, &OsRng.next_u64().to_string());
+
+        let mut req = req.header(USER_AGENT, user_agent);
+
+        if self.opts.forwarded_for {
+            req = req.header("X-Forwarded-For", format!("ARandomCPEN442Miner.{}.{}.x",
+                    OsRng.next_u32(), OsRng.next_u32()));
+        }
+
+        req
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use reqwest::Client;
+    use std::time::Instant;
+
+    fn headers(policy : &HeaderPolicy) -> reqwest::header::HeaderMap {
+        let req = Client::new().post("http://tracker.invalid/claim_coin");
+
+        policy.apply(req)
+            .build()
+            .unwrap()
+            .headers()
+            .clone()
+    }
+
+    #[test]
+    fn test_header_policy_default_ok() {
+        let policy = HeaderPolicy::new(HeaderOpts {
+            user_agents : vec![],
+            forwarded_for : true,
+            jitter : Duration::from_millis(0),
+        }).unwrap();
+
+        let h = headers(&policy);
+        let ua = h.get(USER_AGENT).unwrap().to_str().unwrap();
+        assert!(ua.starts_with("CPEN442 Miner "));
+        assert!(ua["CPEN442 Miner ".len()..].parse::<u64>().is_ok());
+        assert!(h.get("X-Forwarded-For").is_some());
+    }
+
+    #[test]
+    fn test_header_policy_pool_ok() {
+        let pool = vec![String::from("Agent A"), String::from("Agent B")];
+        let policy = HeaderPolicy::new(HeaderOpts {
+            user_agents : pool.clone(),
+            forwarded_for : false,
+            jitter : Duration::from_millis(20),
+        }).unwrap();
+
+        for _ in 0..10 {
+            let h = headers(&policy);
+            let ua = h.get(USER_AGENT).unwrap().to_str().unwrap();
+
+            assert!(pool.iter().any(|p| p == ua));
+            assert!(h.get("X-Forwarded-For").is_none());
+        }
+
+        // Each request waits a different random time up to the jitter
+        let n = 20;
+        let delays : Vec<_> = (0..n).map(|_| {
+            let req = Client::new().post("http://tracker.invalid/claim_coin");
+            let start = Instant::now();
+            policy.apply(req);
+            start.elapsed()
+        }).collect();
+
+        let total : Duration = delays.iter().sum();
+        assert!(total > Duration::from_millis(20));
+        assert!(total < Duration::from_millis(20 * n + 100));
+
+        let mut delays_ms : Vec<_> = delays.iter().map(|d| d.as_millis()).collect();
+        delays_ms.sort();
+        delays_ms.dedup();
+        assert!(delays_ms.len() > 1);
+    }
+
+    #[test]
+    fn test_header_policy_bad() {
+        assert!(HeaderPolicy::new(HeaderOpts {
+            user_agents : vec![" ".into()],
+            forwarded_for : false,
+            jitter : Duration::from_millis(0),
+        }).is_err());
+    }
+}
@@ -3,7 +3,6 @@
 use structopt;
 use structopt::StructOpt;
 use hex;
-use num_cpus;
 use std::path::PathBuf;
 
 mod error;
@@ -15,8 +14,21 @@ mod ocldevice;
 mod cryptowallet;
 mod util;
 mod proxy;
+mod faults;
+mod faketracker;
+mod pooltracker;
+mod replay;
+mod ratelimit;
+mod proxyselect;
+mod proxysource;
+mod headers;
+mod md5;
+mod ocltune;
+mod cpusched;
+mod governor;
 
 use error::Error;
+use cpen442coin::CoinTracker;
 
 #[derive(Debug, StructOpt)]
 struct MinerOclOpts {
@@ -36,6 +48,138 @@ struct MinerOclOpts {
     /// Don't allow OpenCL to take longer than this number of milliseconds
     #[structopt(long = "cl-max-ms")]
     cl_max_ms : Option<u32>,
+
+    /// Find the fastest kernel settings for OpenCL devices that aren't in the tuning file yet
+    #[structopt(long = "cl-autotune")]
+    cl_autotune : bool,
+
+    /// File to keep the tuned kernel settings of each OpenCL device in
+    #[structopt(long = "cl-tuning-file", default_value = "cl_tuning.json", parse(from_os_str))]
+    cl_tuning_file : std::path::PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct MinerFakeOpts {
+    /// Number of leading zeroes the fake tracker requires
+    #[structopt(long = "fake-difficulty", default_value = "9")]
+    fake_difficulty : u64,
+
+    /// Coin the fake tracker starts with, random if not given
+    #[structopt(long = "fake-coin")]
+    fake_coin : Option<String>,
+
+    /// Average coins per hour claimed by a simulated rival on the fake tracker
+    #[structopt(long = "fake-rival-rate", default_value = "0")]
+    fake_rival_rate : f64,
+
+    /// Change the fake tracker's difficulty over time,
+    /// comma separated seconds:difficulty pairs (E.g. 300:10,600:11)
+    #[structopt(long = "fake-difficulty-schedule")]
+    fake_difficulty_schedule : Option<faketracker::DifficultySchedule>,
+}
+
+#[derive(Debug, StructOpt)]
+struct MinerFaultOpts {
+    /// Chance of a tracker request failing with server busy [0 to 1]
+    #[structopt(long = "fault-busy-rate", default_value = "0")]
+    fault_busy_rate : f64,
+
+    /// Chance of a coin claim being rejected as a bad coin [0 to 1]
+    #[structopt(long = "fault-bad-coin-rate", default_value = "0")]
+    fault_bad_coin_rate : f64,
+
+    /// Chance of a tracker request timing out [0 to 1]
+    #[structopt(long = "fault-timeout-rate", default_value = "0")]
+    fault_timeout_rate : f64,
+
+    /// Chance of a tracker request returning malformed JSON [0 to 1]
+    #[structopt(long = "fault-malformed-rate", default_value = "0")]
+    fault_malformed_rate : f64,
+}
+
+#[derive(Debug, StructOpt)]
+struct MinerProxyOpts {
+    /// HTTP Proxies to use, 1 per line
+    #[structopt(long = "proxy-file", parse(from_os_str), number_of_values = 1)]
+    proxy_files : Vec<PathBuf>,
+
+    /// Directories of proxy files, every file in them is used
+    #[structopt(long = "proxy-dir", parse(from_os_str), number_of_values = 1)]
+    proxy_dirs : Vec<PathBuf>,
+
+    /// URLs to download proxy lists from
+    #[structopt(long = "proxy-url", number_of_values = 1)]
+    proxy_urls : Vec<String>,
+
+    /// Commands that print proxies, 1 per line (Run with sh)
+    #[structopt(long = "proxy-cmd", number_of_values = 1)]
+    proxy_cmds : Vec<String>,
+
+    /// How often to download proxy URLs and run proxy commands in seconds
+    #[structopt(long = "proxy-refresh-secs", default_value = "600")]
+    proxy_refresh_secs : u64,
+
+    /// File to keep proxy health in,
+    /// defaults to the first proxy file or directory with .state added
+    #[structopt(long = "proxy-state-file", parse(from_os_str))]
+    proxy_state_file : Option<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct MinerHeaderOpts {
+    /// User agent to send to the tracker, one is picked at random for each request
+    /// if given multiple times. {rand} is replaced with a random number
+    /// [default: "CPEN442 Miner {rand}"]
+    #[structopt(long = "user-agent", number_of_values = 1)]
+    user_agents : Vec<String>,
+
+    /// Don't send a random X-Forwarded-For header to the tracker
+    #[structopt(long = "no-forwarded-for")]
+    no_forwarded_for : bool,
+
+    /// Wait a random time up to this many milliseconds before each tracker request
+    #[structopt(long = "request-jitter-ms", default_value = "0")]
+    request_jitter_ms : u64,
+}
+
+#[derive(Debug, StructOpt)]
+struct MinerRateOpts {
+    /// Tracker requests per minute without a proxy,
+    /// comma separated last_coin,difficulty,claim_coin limits
+    #[structopt(long = "direct-rate-limits", default_value = "5,2,10")]
+    direct_rate_limits : ratelimit::RateLimits,
+
+    /// Tracker requests per minute for each proxy,
+    /// comma separated last_coin,difficulty,claim_coin limits
+    #[structopt(long = "proxy-rate-limits", default_value = "5,2,10")]
+    proxy_rate_limits : ratelimit::RateLimits,
+}
+
+#[derive(Debug, StructOpt)]
+struct MinerCpuOpts {
+    /// Pin CPU miners to these cores, E.g. 0-7,16-23
+    #[structopt(long = "cpu-cores")]
+    cpu_cores : Option<cpusched::CpuList>,
+
+    /// Pin CPU miners to the cores of these NUMA nodes
+    #[structopt(long = "numa-node")]
+    numa_nodes : Vec<usize>,
+
+    /// Nice value of the CPU miner threads [0 to 19]
+    #[structopt(long = "cpu-nice")]
+    cpu_nice : Option<i32>,
+
+    /// Run CPU miner threads with SCHED_IDLE so they only use otherwise idle cores
+    #[structopt(long = "cpu-sched-idle")]
+    cpu_sched_idle : bool,
+
+    /// Run fewer CPU miners while they and other programs would keep more cores than this busy
+    #[structopt(long = "cpu-max-load")]
+    cpu_max_load : Option<f64>,
+
+    /// Run fewer CPU miners while the hottest thermal zone is above this many degrees Celsius
+    #[structopt(long = "cpu-max-temp")]
+    cpu_max_temp : Option<f64>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -60,16 +204,47 @@ struct MinerOpts {
     #[structopt(short = "o", long = "output", parse(from_os_str))]
     wallet : Option<PathBuf>,
 
-    /// HTTP Proxies to use
-    #[structopt(long = "proxy-file", parse(from_os_str))]
-    http_proxies : Option<PathBuf>,
+    /// Record all tracker requests and responses to this file
+    #[structopt(long = "record", parse(from_os_str))]
+    record : Option<PathBuf>,
+
+    /// Replay tracker responses from a file made with --record
+    #[structopt(long = "replay", parse(from_os_str), conflicts_with_all = &["fake", "pool",
+        "fault-busy-rate", "fault-bad-coin-rate", "fault-timeout-rate", "fault-malformed-rate"])]
+    replay : Option<PathBuf>,
+
+    /// Mine for the pool at this URL, the identity names this worker to the pool
+    #[structopt(long = "pool")]
+    pool : Option<String>,
 
     /// How often to poll last_coin in milliseconds
     #[structopt(long = "poll-ms", default_value = "6000")]
     poll_ms : u32,
 
+    /// Benchmark the miners for this many seconds each instead of mining
+    #[structopt(long = "bench-secs")]
+    bench_secs : Option<u64>,
+
+    #[structopt(flatten)]
+    cpu : MinerCpuOpts,
+
     #[structopt(flatten)]
     ocl : MinerOclOpts,
+
+    #[structopt(flatten)]
+    fake_opts : MinerFakeOpts,
+
+    #[structopt(flatten)]
+    fault_opts : MinerFaultOpts,
+
+    #[structopt(flatten)]
+    rate_opts : MinerRateOpts,
+
+    #[structopt(flatten)]
+    proxy_opts : MinerProxyOpts,
+
+    #[structopt(flatten)]
+    header_opts : MinerHeaderOpts,
 }
 
 fn main() -> Result<(), Error> {
@@ -104,27 +279,46 @@ fn main() -> Result<(), Error> {
 
     println!("Mining with Identity: {}", identity);
 
-    let ncpu = opt.ncpu.unwrap_or(num_cpus::get());
+    let mut cores = opt.cpu.cpu_cores.map(|l| l.0).unwrap_or_default();
+    for node in &opt.cpu.numa_nodes {
+        cores.extend(cpusched::numa_node_cores(*node)?);
+    }
+
+    let priority = if opt.cpu.cpu_sched_idle {
+        cpusched::Priority::Idle
+    } else if let Some(nice) = opt.cpu.cpu_nice {
+        cpusched::Priority::Nice(nice)
+    } else {
+        cpusched::Priority::Normal
+    };
+
+    // One core feeds the OpenCL queues
+    let placement = cpusched::Placement::new(cores, priority, opt.ocl.cl_device_idxs.is_some());
+
+    let ncpu = opt.ncpu.unwrap_or(placement.default_ncpu());
 
     println!("Using {} cpu cores to mine", ncpu);
 
-    let tracker;
-    let mut wallet = None;
-    if opt.fake {
-        println!("WARNING: Using Fake Tracker, Coins Not Recorded!");
-        tracker = cpen442coin::Tracker::new_fake(identity.clone())?;
+    let governor = if opt.cpu.cpu_max_load.is_some() || opt.cpu.cpu_max_temp.is_some() {
+        Some(governor::Governor::new(governor::GovernorOpts {
+            max_load : opt.cpu.cpu_max_load,
+            max_temp : opt.cpu.cpu_max_temp,
+        }, ncpu)?)
     } else {
-        tracker = cpen442coin::Tracker::new(identity.clone(),
-            opt.http_proxies.unwrap_or(PathBuf::new()))?;
+        None
+    };
 
-        if let Some(wallet_path) = opt.wallet {
-            println!("Wallet Path: {:?}", wallet_path);
-            wallet = Some(cryptowallet::Wallet::new(wallet_path, identity.clone())?);
-        }
-    }
+    let faults = faults::FaultInjector::new(faults::FaultRates {
+        server_busy : opt.fault_opts.fault_busy_rate,
+        bad_coin : opt.fault_opts.fault_bad_coin_rate,
+        timeout : opt.fault_opts.fault_timeout_rate,
+        malformed : opt.fault_opts.fault_malformed_rate,
+    })?;
 
     let mut all_oclfs = Vec::new();
     if let Some(cl_device_idxs) = opt.ocl.cl_device_idxs {
+        let mut tuning = ocltune::Tuning::load(&opt.ocl.cl_tuning_file)?;
+
         for idx in cl_device_idxs {
             let devices = ocldevice::get_cl_devices()?;
 
@@ -146,13 +340,124 @@ fn main() -> Result<(), Error> {
                 oclf.set_max_loop_ms(ms);
             }
 
+            let descriptor = oclf.device_descriptor();
+            if let Some(config) = tuning.get(&descriptor) {
+                println!("Using Tuned OpenCL Settings: {:?}", config);
+                oclf = oclf.with_config(config)?;
+            } else if opt.ocl.cl_autotune {
+                let config = ocltune::autotune(&oclf)?;
+                tuning.set(&descriptor, config)?;
+                oclf = oclf.with_config(config)?;
+            }
+
             all_oclfs.push(oclf);
         }
     }
 
-    let mut mm = miner::MiningManager::new(tracker, ncpu, all_oclfs, opt.poll_ms);
+    if let Some(secs) = opt.bench_secs {
+        return bench(&identity, ncpu, all_oclfs, std::time::Duration::from_secs(secs));
+    }
 
-    mm.run(&mut wallet)?;
+    if faults.enabled() {
+        println!("WARNING: Injecting Faults Into Tracker Requests!");
+    }
+
+    let header_policy = headers::HeaderPolicy::new(headers::HeaderOpts {
+        user_agents : opt.header_opts.user_agents,
+        forwarded_for : ! opt.header_opts.no_forwarded_for,
+        jitter : std::time::Duration::from_millis(opt.header_opts.request_jitter_ms),
+    })?;
+
+    let tracker : Box<dyn CoinTracker>;
+    let mut wallet = None;
+    if let Some(replay_path) = opt.replay {
+        println!("WARNING: Replaying Tracker Recording {:?}, Coins Not Recorded!", replay_path);
+        tracker = Box::new(replay::ReplayTracker::new(identity.clone(), replay_path)?);
+    } else if opt.fake {
+        println!("WARNING: Using Fake Tracker, Coins Not Recorded!");
+        tracker = Box::new(faketracker::FakeTracker::new(identity.clone(),
+            faketracker::FakeTrackerOpts {
+                difficulty : opt.fake_opts.fake_difficulty,
+                start_coin : opt.fake_opts.fake_coin,
+                rival_coins_per_hour : opt.fake_opts.fake_rival_rate,
+                difficulty_schedule : opt.fake_opts.fake_difficulty_schedule,
+            }, faults)?);
+    } else if let Some(pool_url) = opt.pool {
+        println!("Mining For Pool: {}", pool_url);
+        tracker = Box::new(pooltracker::PoolTracker::new(identity.clone(), &pool_url, faults,
+            header_policy, opt.rate_opts.direct_rate_limits)?);
+    } else {
+        let po = opt.proxy_opts;
+        let mut sources = Vec::new();
+        sources.extend(po.proxy_files.into_iter().map(proxysource::ProxySource::File));
+        sources.extend(po.proxy_dirs.into_iter().map(proxysource::ProxySource::Dir));
+        sources.extend(po.proxy_urls.into_iter().map(proxysource::ProxySource::Url));
+        sources.extend(po.proxy_cmds.into_iter().map(proxysource::ProxySource::Command));
+
+        tracker = Box::new(cpen442coin::HttpTracker::new(identity.clone(),
+            proxy::ProxyOpts {
+                sources,
+                refresh : std::time::Duration::from_secs(po.proxy_refresh_secs),
+                state_file : po.proxy_state_file,
+                limits : opt.rate_opts.proxy_rate_limits,
+            }, faults, header_policy, opt.rate_opts.direct_rate_limits)?);
+
+        if let Some(wallet_path) = opt.wallet {
+            println!("Wallet Path: {:?}", wallet_path);
+            wallet = Some(cryptowallet::Wallet::new(wallet_path, identity.clone())?);
+        }
+    }
+
+    if let Some(record_path) = opt.record {
+        println!("Recording Tracker Requests To: {:?}", record_path);
+        let tracker = replay::RecordingTracker::new(tracker, record_path)?;
+        let mut mm = miner::MiningManager::new(tracker, ncpu, all_oclfs, opt.poll_ms, placement, governor);
+
+        mm.run(&mut wallet)
+    } else {
+        let mut mm = miner::MiningManager::new(tracker, ncpu, all_oclfs, opt.poll_ms, placement, governor);
+
+        mm.run(&mut wallet)
+    }
+}
+
+/// Print the hash rate of the CPU and OpenCL miners, for OpenCL compare the
+/// kernel variants and reusing buffers between kernel launches against allocating new ones.
+fn bench(identity : &str, ncpu : usize, oclfs : Vec<oclminer::OclMinerFunction>,
+    duration : std::time::Duration) -> Result<(), Error> {
+    use oclminer::KernelVariant;
+
+    let mhash = |rate : f64| rate / 1e6;
+
+    if ncpu > 0 {
+        let mut simd_levels = vec![None];
+        simd_levels.extend(md5::SimdLevel::detect().map(Some));
+
+        for simd in simd_levels {
+            let rate = miner::bench_miner(cpuminer::CpuMinerFunction::with_simd(simd), identity, duration)?;
+            let name = simd.map(|l| format!("{:?}", l)).unwrap_or_else(|| String::from("OpenSSL"));
+            println!("CPU (1 core, {}): {:.2} MHash/s", name, mhash(rate));
+        }
+    }
+
+    for (i, oclf) in oclfs.into_iter().enumerate() {
+        let mut realloc_oclf = oclf.clone();
+        realloc_oclf.set_realloc_buffers(true);
+
+        let mut cases = Vec::new();
+        for variant in KernelVariant::ALL.iter() {
+            let config = oclminer::KernelConfig { variant : *variant, ..oclf.config() };
+            cases.push((format!("{:?} Kernel", variant), oclf.with_config(config)?));
+        }
+        cases.push((String::from("New Buffers Every Launch"), realloc_oclf));
+
+        for (name, case_oclf) in cases {
+            let config = case_oclf.config();
+            let rate = miner::bench_miner(case_oclf, identity, duration)?;
+
+            println!("OpenCL Device #{} {} ({:?}): {:.2} MHash/s", i, name, config, mhash(rate));
+        }
+    }
 
     Ok(())
 }
@@ -0,0 +1,290 @@
+//! MD5 Compression
+//!
+//! The MD5 block function on its own, used to hash the leading
+//! blocks of a message once so the miners can start from the
+//! midstate instead of hashing those blocks again for every try.
+//! The SIMD version hashes a block of several messages at once.
+
+/// Number of 32 bit words in a block
+pub const MD5_BLOCK_WORDS : usize = 16;
+
+/// State before any block is hashed
+pub const MD5_INIT : [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+
+const K : [u32; 64] = [
+    0xd76aa478, 0xe8c7b756, 0x242070db, 0xc1bdceee, 0xf57c0faf, 0x4787c62a, 0xa8304613, 0xfd469501,
+    0x698098d8, 0x8b44f7af, 0xffff5bb1, 0x895cd7be, 0x6b901122, 0xfd987193, 0xa679438e, 0x49b40821,
+    0xf61e2562, 0xc040b340, 0x265e5a51, 0xe9b6c7aa, 0xd62f105d, 0x02441453, 0xd8a1e681, 0xe7d3fbc8,
+    0x21e1cde6, 0xc33707d6, 0xf4d50d87, 0x455a14ed, 0xa9e3e905, 0xfcefa3f8, 0x676f02d9, 0x8d2a4c8a,
+    0xfffa3942, 0x8771f681, 0x6d9d6122, 0xfde5380c, 0xa4beea44, 0x4bdecfa9, 0xf6bb4b60, 0xbebfbc70,
+    0x289b7ec6, 0xeaa127fa, 0xd4ef3085, 0x04881d05, 0xd9d4d039, 0xe6db99e5, 0x1fa27cf8, 0xc4ac5665,
+    0xf4292244, 0x432aff97, 0xab9423a7, 0xfc93a039, 0x655b59c3, 0x8f0ccc92, 0xffeff47d, 0x85845dd1,
+    0x6fa87e4f, 0xfe2ce6e0, 0xa3014314, 0x4e0811a1, 0xf7537e82, 0xbd3af235, 0x2ad7d2bb, 0xeb86d391,
+];
+
+const S : [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+
+/// Run the first n_steps steps, returns the working registers
+fn md5_steps(state : &[u32; 4], block : &[u32; MD5_BLOCK_WORDS], n_steps : usize) -> [u32; 4] {
+    let [mut a, mut b, mut c, mut d] = *state;
+
+    for i in 0..n_steps {
+        let (f, g) = match i / 16 {
+            0 => ((b & c) | (!b & d), i),
+            1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+            2 => (b ^ c ^ d, (3 * i + 5) % 16),
+            _ => (c ^ (b | !d), (7 * i) % 16),
+        };
+
+        let rotated = a.wrapping_add(f)
+            .wrapping_add(K[i])
+            .wrapping_add(block[g])
+            .rotate_left(S[(i / 16) * 4 + i % 4]);
+
+        a = d;
+        d = c;
+        c = b;
+        b = b.wrapping_add(rotated);
+    }
+
+    [a, b, c, d]
+}
+
+/// Hash one block (16 little endian words) into the state
+pub fn md5_compress(state : &mut [u32; 4], block : &[u32; MD5_BLOCK_WORDS]) {
+    let [a, b, c, d] = md5_steps(state, block, 64);
+
+    state[0] = state[0].wrapping_add(a);
+    state[1] = state[1].wrapping_add(b);
+    state[2] = state[2].wrapping_add(c);
+    state[3] = state[3].wrapping_add(d);
+}
+
+/// State after hashing whole blocks of a message, bytes.len() must be a multiple of 64
+pub fn md5_midstate(bytes : &[u8]) -> [u32; 4] {
+    assert_eq!(bytes.len() % (MD5_BLOCK_WORDS * 4), 0);
+
+    let mut state = MD5_INIT;
+
+    for block_bytes in bytes.chunks(MD5_BLOCK_WORDS * 4) {
+        let mut block = [0u32; MD5_BLOCK_WORDS];
+
+        for (w, b) in block.iter_mut().zip(block_bytes.chunks(4)) {
+            *w = u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        }
+
+        md5_compress(&mut state, &block);
+    }
+
+    state
+}
+
+/// Vector instruction sets md5_compress_lanes can use
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SimdLevel {
+    /// 4 lanes
+    Sse2,
+    /// 8 lanes
+    Avx2,
+}
+
+/// Most lanes of any SimdLevel
+pub const MAX_LANES : usize = 8;
+
+impl SimdLevel {
+    /// The widest level the CPU supports, None if it has none
+    pub fn detect() -> Option<Self> {
+        [SimdLevel::Avx2, SimdLevel::Sse2].iter().cloned().find(|l| l.supported())
+    }
+
+    pub fn supported(self) -> bool {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            match self {
+                SimdLevel::Sse2 => is_x86_feature_detected!("sse2"),
+                SimdLevel::Avx2 => is_x86_feature_detected!("avx2"),
+            }
+        }
+
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        {
+            false
+        }
+    }
+
+    /// Messages hashed at once
+    pub fn lanes(self) -> usize {
+        match self {
+            SimdLevel::Sse2 => 4,
+            SimdLevel::Avx2 => 8,
+        }
+    }
+}
+
+/// Hash one block into the state of each lane at once, words are laid out [word][lane].
+/// Only the first level.lanes() lanes are used.
+pub fn md5_compress_lanes(level : SimdLevel, state : &mut [[u32; MAX_LANES]; 4],
+    block : &[[u32; MAX_LANES]; MD5_BLOCK_WORDS]) {
+    assert!(level.supported(), "{:?} is not supported by this CPU", level);
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    unsafe {
+        match level {
+            SimdLevel::Sse2 => simd::compress_sse2(state, block),
+            SimdLevel::Avx2 => simd::compress_avx2(state, block),
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+mod simd {
+    #[cfg(target_arch = "x86")]
+    use std::arch::x86::*;
+    #[cfg(target_arch = "x86_64")]
+    use std::arch::x86_64::*;
+    use super::{K, S, MAX_LANES, MD5_BLOCK_WORDS};
+
+    /// Same steps as md5_steps on vectors of lanes, the vector operations are passed in
+    macro_rules! md5_lanes {
+        ($state:ident, $block:ident, $vec:ty, $loadu:ident, $storeu:ident, $set1:ident,
+            $add:ident, $xor:ident, $and:ident, $or:ident, $andnot:ident, $sll:ident, $srl:ident) => {{
+            let mut x : [$vec; MD5_BLOCK_WORDS] = [$set1(0); MD5_BLOCK_WORDS];
+            for (x, w) in x.iter_mut().zip($block.iter()) {
+                *x = $loadu(w.as_ptr() as *const $vec);
+            }
+
+            let mut s : [$vec; 4] = [$set1(0); 4];
+            for (s, w) in s.iter_mut().zip($state.iter()) {
+                *s = $loadu(w.as_ptr() as *const $vec);
+            }
+
+            let [mut a, mut b, mut c, mut d] = s;
+            let ones = $set1(-1);
+
+            for i in 0..64 {
+                let (f, g) = match i / 16 {
+                    0 => ($or($and(b, c), $andnot(b, d)), i),
+                    1 => ($or($and(d, b), $andnot(d, c)), (5 * i + 1) % 16),
+                    2 => ($xor($xor(b, c), d), (3 * i + 5) % 16),
+                    _ => ($xor(c, $or(b, $xor(d, ones))), (7 * i) % 16),
+                };
+
+                let sum = $add($add(a, f), $add($set1(K[i] as i32), x[g]));
+                let rot = S[(i / 16) * 4 + i % 4] as i32;
+                let rotated = $or($sll(sum, _mm_cvtsi32_si128(rot)), $srl(sum, _mm_cvtsi32_si128(32 - rot)));
+
+                a = d;
+                d = c;
+                c = b;
+                b = $add(b, rotated);
+            }
+
+            for (w, v) in $state.iter_mut().zip([a, b, c, d].iter()) {
+                let sum = $add($loadu(w.as_ptr() as *const $vec), *v);
+                $storeu(w.as_mut_ptr() as *mut $vec, sum);
+            }
+        }};
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn compress_sse2(state : &mut [[u32; MAX_LANES]; 4],
+        block : &[[u32; MAX_LANES]; MD5_BLOCK_WORDS]) {
+        md5_lanes!(state, block, __m128i, _mm_loadu_si128, _mm_storeu_si128, _mm_set1_epi32,
+            _mm_add_epi32, _mm_xor_si128, _mm_and_si128, _mm_or_si128, _mm_andnot_si128,
+            _mm_sll_epi32, _mm_srl_epi32)
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn compress_avx2(state : &mut [[u32; MAX_LANES]; 4],
+        block : &[[u32; MAX_LANES]; MD5_BLOCK_WORDS]) {
+        md5_lanes!(state, block, __m256i, _mm256_loadu_si256, _mm256_storeu_si256, _mm256_set1_epi32,
+            _mm256_add_epi32, _mm256_xor_si256, _mm256_and_si256, _mm256_or_si256, _mm256_andnot_si256,
+            _mm256_sll_epi32, _mm256_srl_epi32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::hash::{hash, MessageDigest};
+
+    /// The first word md5_compress would leave in the state, found without the
+    /// last 3 steps like the early reject kernel (its own steps are checked in oclminer)
+    fn md5_compress_first_word(state : &[u32; 4], block : &[u32; MD5_BLOCK_WORDS]) -> u32 {
+        // Step 60 is the last step that changes the first word and the
+        // register rotation leaves its value in b
+        state[0].wrapping_add(md5_steps(state, block, 61)[1])
+    }
+
+    /// Padding block for a message of whole blocks, like the miners use
+    fn pad(bytes : &[u8]) -> Vec<u8> {
+        let mut padded = Vec::from(bytes);
+
+        padded.push(0x80);
+        padded.resize(bytes.len() + 56, 0);
+        padded.extend_from_slice(&((bytes.len() as u64) * 8).to_le_bytes());
+
+        padded
+    }
+
+    #[test]
+    fn test_md5_midstate_ok() {
+        for len in &[0, 64, 256] {
+            let mut message = vec![0u8; *len];
+            openssl::rand::rand_bytes(&mut message).unwrap();
+
+            let state = md5_midstate(&pad(&message));
+
+            let digest : Vec<u8> = state.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect();
+            assert_eq!(digest, hash(MessageDigest::md5(), &message).unwrap().to_vec());
+        }
+    }
+
+    #[test]
+    fn test_md5_early_reject_ok() {
+        use rand::{RngCore, rngs::OsRng};
+
+        // Same accept/reject decisions as the full compression
+        for _ in 0..10000 {
+            let mut state = [0u32; 4];
+            let mut block = [0u32; MD5_BLOCK_WORDS];
+            state.iter_mut().chain(block.iter_mut()).for_each(|w| *w = OsRng.next_u32());
+
+            let first_word = md5_compress_first_word(&state, &block);
+            md5_compress(&mut state, &block);
+
+            assert_eq!(first_word, state[0]);
+        }
+    }
+
+    #[test]
+    fn test_md5_compress_lanes_ok() {
+        use rand::{RngCore, rngs::OsRng};
+
+        for level in [SimdLevel::Sse2, SimdLevel::Avx2].iter().filter(|l| l.supported()) {
+            let mut state = [[0u32; MAX_LANES]; 4];
+            let mut block = [[0u32; MAX_LANES]; MD5_BLOCK_WORDS];
+            state.iter_mut().chain(block.iter_mut())
+                .for_each(|w| w.iter_mut().for_each(|l| *l = OsRng.next_u32()));
+
+            let mut lane_states = state;
+            md5_compress_lanes(*level, &mut lane_states, &block);
+
+            for lane in 0..MAX_LANES {
+                let mut expected = [0u32; 4];
+                let mut lane_block = [0u32; MD5_BLOCK_WORDS];
+                expected.iter_mut().enumerate().for_each(|(w, e)| *e = state[w][lane]);
+                lane_block.iter_mut().enumerate().for_each(|(w, e)| *e = block[w][lane]);
+
+                // Unused lanes are left alone
+                if lane < level.lanes() {
+                    md5_compress(&mut expected, &lane_block);
+                }
+
+                for w in 0..4 {
+                    assert_eq!(lane_states[w][lane], expected[w], "{:?} lane {}", level, lane);
+                }
+            }
+        }
+    }
+}
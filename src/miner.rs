@@ -5,14 +5,16 @@ use std::collections::VecDeque;
 use crate::{
     error::Error,
     cpen442coin,
+    cpen442coin::CoinTracker,
     cryptowallet::Wallet,
     util::*,
     cpuminer::{CpuMiner, CpuMinerFunction},
+    cpusched::{Placement, ThreadSched},
+    governor::Governor,
     oclminer::{OclMiner, OclMinerFunction}
 };
 use openssl::hash::{Hasher, MessageDigest};
 use console::Term;
-use atomic_option::AtomicOption;
 use std::time::{Instant, Duration};
 use std::thread;
 use std::sync::{
@@ -31,8 +33,8 @@ pub struct Coin {
     pub blob : Vec<u8>
 }
 
-pub struct MiningManager {
-    tracker : cpen442coin::Tracker,
+pub struct MiningManager<T : CoinTracker> {
+    tracker : T,
     stats_rchan : mpsc::Receiver<Stats>,
     stats_schan : mpsc::SyncSender<Stats>,
     coins_rchan : mpsc::Receiver<Coin>,
@@ -40,15 +42,21 @@ pub struct MiningManager {
     nproducers : usize,
     poll_ms : u32,
     miners : VecDeque<CpuMiner>,
+    /// CPU miners told to stop by the governor that haven't returned yet
+    stopping_miners : Vec<CpuMiner>,
     oclminers : VecDeque<OclMiner>,
     oclminerfuncs : VecDeque<OclMinerFunction>,
+    placement : Placement,
+    governor : Option<Governor>,
 }
 
-impl MiningManager {
-    pub fn new(tracker : cpen442coin::Tracker,
+impl<T : CoinTracker> MiningManager<T> {
+    pub fn new(tracker : T,
         ncpu : usize,
         oclf : Vec<OclMinerFunction>,
-        poll_ms : u32) -> Self {
+        poll_ms : u32,
+        placement : Placement,
+        governor : Option<Governor>) -> Self {
         let nproducers = ncpu;
         let (stats_schan, stats_rchan) = mpsc::sync_channel(16 * ncpu + 16);
         let (coins_schan, coins_rchan) = mpsc::sync_channel(2);
@@ -65,8 +73,11 @@ impl MiningManager {
             nproducers,
             poll_ms,
             miners,
+            stopping_miners : Vec::new(),
             oclminerfuncs,
             oclminers,
+            placement,
+            governor,
         }
     }
 
@@ -78,7 +89,8 @@ impl MiningManager {
             coin_schan : self.coins_schan.clone(),
             previous_coin : String::from(last_coin),
             difficulty,
-            miner_id : String::from(self.tracker.id())
+            miner_id : String::from(self.tracker.id()),
+            sched : self.placement.cpu_miner(self.miners.len()),
         });
 
         self.miners.push_back(miner);
@@ -96,7 +108,8 @@ impl MiningManager {
                     coin_schan : self.coins_schan.clone(),
                     previous_coin : String::from(last_coin),
                     difficulty,
-                    miner_id : String::from(self.tracker.id())
+                    miner_id : String::from(self.tracker.id()),
+                    sched : self.placement.ocl_miner(),
                 }
             );
 
@@ -127,7 +140,16 @@ impl MiningManager {
         }
     }
 
+    /// Miners keep running after finding a coin, so only those that failed
+    /// stop on their own and get restarted
     fn prune_stopped_miners(&mut self) {
+        for i in 0..self.stopping_miners.len() {
+            if self.stopping_miners[i].is_stopped() {
+                self.stopping_miners.remove(i).stop().unwrap();
+                break;
+            }
+        }
+
         for i in 0..self.miners.len() {
             if self.miners[i].is_stopped() {
                 self.miners[i].stop().unwrap();
@@ -145,11 +167,46 @@ impl MiningManager {
         }
     }
 
+    /// Let the governor change the number of CPU miners, extra miners are told to stop
+    /// without waiting for them so coins they are sending don't block the manager
+    fn govern_cpu_miners(&mut self, term : &Term) {
+        if let Some(governor) = &mut self.governor {
+            let nproducers = governor.update();
+
+            if nproducers != self.nproducers {
+                term.write_line(&format!("\nRunning {} CPU Miners", nproducers)).unwrap();
+                self.nproducers = nproducers;
+            }
+        }
+
+        while self.miners.len() > self.nproducers {
+            let miner = self.miners.pop_back().unwrap();
+            miner.send(MinerCommand::Stop);
+            self.stopping_miners.push(miner);
+        }
+    }
+
     pub fn run(&mut self, wallet : &mut Option<Wallet>) -> Result<(), Error> {
         let term = Term::stderr();
-        self.tracker.start_last_coin_thread(self.poll_ms);
-        let mut last_coin = self.tracker.get_last_coin()?;
-        let mut last_num_zeros = self.tracker.get_difficulty()?;
+        self.tracker.start(self.poll_ms);
+        let mut last_coin = loop {
+            match self.tracker.last_coin() {
+                Ok(coin) => break coin,
+                Err(e) => {
+                    term.write_line(&format!("Failed to get last coin: {:?}", e)).unwrap();
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        };
+        let mut last_num_zeros = loop {
+            match self.tracker.difficulty() {
+                Ok(num_zeros) => break num_zeros,
+                Err(e) => {
+                    term.write_line(&format!("Failed to get difficulty: {:?}", e)).unwrap();
+                    thread::sleep(Duration::from_secs(1));
+                }
+            }
+        };
 
         let start_time = Instant::now();
         let mut stat_start_time = Instant::now();
@@ -167,6 +224,8 @@ impl MiningManager {
         term.write_line(&format!("Difficulty: {}", last_num_zeros)).unwrap();
 
         loop {
+            self.govern_cpu_miners(&term);
+
             if self.miners.len() < self.nproducers {
                 self.start_new_miner(&last_coin, last_num_zeros);
             }
@@ -238,7 +297,7 @@ impl MiningManager {
                     if hash_starts_n_zeroes(&h[..], last_num_zeros) {
                         term.write_line(&format!("\nTrying to claim coin with hash: {}", coinhash)).unwrap();
 
-                        match self.tracker.claim_coin(coin.blob.clone(), coin.previous_coin.clone(), &coinhash) {
+                        match self.tracker.claim(coin.blob.clone(), coin.previous_coin.clone(), &coinhash) {
                             Ok(_) => {
                                 term.write_line("Coin successfully claimed!").unwrap();
 
@@ -282,7 +341,7 @@ impl MiningManager {
             }
 
             if coin_check_timer.check_and_reset() {
-                match self.tracker.get_last_coin() {
+                match self.tracker.last_coin() {
                     Ok(coin) => {
                         if coin != last_coin {
                             last_coin = coin;
@@ -297,7 +356,7 @@ impl MiningManager {
                     }
                 };
 
-                match self.tracker.get_difficulty() {
+                match self.tracker.difficulty() {
                     Ok(num_zeros) => {
                         if num_zeros != last_num_zeros {
                             last_num_zeros = num_zeros;
@@ -321,12 +380,81 @@ impl MiningManager {
     }
 }
 
+/// Run a miner alone against a random coin without claiming anything,
+/// returns its hash rate in hashes per second
+pub fn bench_miner<F>(miner_func : F, miner_id : &str, duration : Duration) -> Result<f64, Error>
+    where F : MinerFunction + Sized + Send + 'static {
+    // Too hard to find coins so the miner never stops
+    const BENCH_NUM_ZEROS : u64 = 15;
+
+    let (stats_schan, stats_rchan) = mpsc::sync_channel(64);
+    let (coins_schan, coins_rchan) = mpsc::sync_channel(64);
+
+    let mut coin = [0u8; cpen442coin::MD5_HASH_LEN];
+    openssl::rand::rand_bytes(&mut coin[4..])?;
+
+    let mut miner = Miner::new(miner_func, MinerParams {
+        stats_schan,
+        coin_schan : coins_schan,
+        previous_coin : hex::encode(&coin[..]),
+        difficulty : BENCH_NUM_ZEROS,
+        miner_id : String::from(miner_id),
+        sched : ThreadSched::default(),
+    });
+
+    let start = Instant::now();
+    let mut hash_count = 0;
+    let mut last_stat = start;
+
+    miner.run();
+
+    while start.elapsed() < duration && ! miner.is_stopped() {
+        while let Ok(stat) = stats_rchan.try_recv() {
+            hash_count += stat.nhash;
+            last_stat = Instant::now();
+        }
+
+        while let Ok(_) = coins_rchan.try_recv() {}
+
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    miner.stop()?;
+
+    let elapsed = (last_stat - start).as_secs_f64();
+
+    if elapsed > 0.0 {
+        Ok(hash_count as f64 / elapsed)
+    } else {
+        Err(Error::Msg("Benchmark too short, no hashes reported".into()))
+    }
+}
+
 struct MinerParams {
     pub stats_schan : mpsc::SyncSender<Stats>,
     pub coin_schan : mpsc::SyncSender<Coin>,
     pub previous_coin : String,
     pub difficulty : u64,
     pub miner_id : String,
+    pub sched : ThreadSched,
+}
+
+/// Commands sent to a running miner
+pub enum MinerCommand {
+    /// Mine on a new previous coin
+    Coin(String),
+    /// The number of leading zeroes needed changed
+    Difficulty(u64),
+    /// Finish up and return
+    Stop,
+}
+
+/// Commands received since the last check, only the latest coin and difficulty matter
+#[derive(Default)]
+pub struct MinerUpdate {
+    pub previous_coin : Option<String>,
+    pub difficulty : Option<u64>,
+    pub stop : bool,
 }
 
 /// Data taken into the thread
@@ -334,43 +462,68 @@ pub struct MinerThreadData {
     pub stats_schan : mpsc::SyncSender<Stats>,
     pub coin_schan : mpsc::SyncSender<Coin>,
     pub miner_id : String,
+    /// Coin and difficulty to start mining with
+    pub previous_coin : String,
+    pub difficulty : u64,
+    commands : mpsc::Receiver<MinerCommand>,
 }
 
-/// Data shared with the thread
-/// managed by a mutex
-pub struct MinerSharedData {
-    pub previous_coin : AtomicOption<String>,
-    pub difficulty : AtomicOption<u64>,
-    pub should_stop : AtomicBool,
+impl MinerThreadData {
+    /// Take the commands sent since the last call without waiting,
+    /// the miner is told to stop if its Miner is gone
+    pub fn updates(&self) -> MinerUpdate {
+        let mut update = MinerUpdate::default();
+
+        loop {
+            match self.commands.try_recv() {
+                Ok(MinerCommand::Coin(coin)) => update.previous_coin = Some(coin),
+                Ok(MinerCommand::Difficulty(difficulty)) => update.difficulty = Some(difficulty),
+                Ok(MinerCommand::Stop) | Err(mpsc::TryRecvError::Disconnected) => {
+                    update.stop = true;
+                    break;
+                },
+                Err(mpsc::TryRecvError::Empty) => break,
+            }
+        }
+
+        update
+    }
 }
 
+/// Mines until told to stop, reporting coins found on the coin channel
 pub trait MinerFunction {
-    fn run(self, tdata: MinerThreadData, tsdata: Arc<MinerSharedData>) -> Result<(), Error>;
+    fn run(self, tdata: MinerThreadData) -> Result<(), Error>;
 }
 
 pub struct Miner<T : MinerFunction + Sized + Send + 'static>
 {
     miner_func : Option<T>,
     tdata : Option<MinerThreadData>,
-    tsdata : Arc<MinerSharedData>,
+    commands : mpsc::Sender<MinerCommand>,
+    /// Set when the thread returns
+    stopped : Arc<AtomicBool>,
+    sched : ThreadSched,
     thread : Option<thread::JoinHandle<Result<(), Error>>>
 }
 
 impl<T: MinerFunction + Sized + Send + 'static> Miner<T>
 {
     fn new(miner_func : T, params : MinerParams) -> Self {
+        let (commands, commands_rchan) = mpsc::channel();
+
         Miner {
             miner_func : Some(miner_func),
             tdata : Some(MinerThreadData {
                 stats_schan : params.stats_schan,
                 coin_schan : params.coin_schan,
                 miner_id : params.miner_id,
+                previous_coin : params.previous_coin,
+                difficulty : params.difficulty,
+                commands : commands_rchan,
             }),
-            tsdata : Arc::from(MinerSharedData {
-                previous_coin : AtomicOption::from(Some(Box::from(params.previous_coin))),
-                difficulty : AtomicOption::from(Some(Box::from(params.difficulty))),
-                should_stop : AtomicBool::new(false),
-            }),
+            commands,
+            stopped : Arc::new(AtomicBool::new(false)),
+            sched : params.sched,
             thread : None
         }
     }
@@ -378,39 +531,40 @@ impl<T: MinerFunction + Sized + Send + 'static> Miner<T>
     fn run(&mut self) {
         let miner_func = self.miner_func.take().unwrap();
         let tdata = self.tdata.take().unwrap();
-        let tsdata = self.tsdata.clone();
+        let stopped = self.stopped.clone();
+        let sched = self.sched.clone();
 
         self.thread = Some(thread::spawn(move || -> Result<(), Error> {
-            let miner_func = miner_func;
-            let tdata = tdata;
-            let tsdata = tsdata;
-            match miner_func.run(tdata, tsdata.clone()) {
-                Ok(_) => {
-                    tsdata.should_stop.store(true, Ordering::Relaxed);
-                    Ok(())
-                },
-                Err(e) => {
-                    tsdata.should_stop.store(true, Ordering::Relaxed);
-                    Err(e)
-                }
+            // Still mine if the thread can't be placed
+            if let Err(e) = sched.apply() {
+                println!("\nWARNING: {:?}", e);
             }
+
+            let result = miner_func.run(tdata);
+            stopped.store(true, Ordering::Relaxed);
+            result
         }));
     }
 
+    /// Send a command, a miner that already stopped ignores it
+    fn send(&self, command : MinerCommand) {
+        self.commands.send(command).ok();
+    }
+
     fn update_prev_coin(&self, coin : String) {
-        self.tsdata.previous_coin.replace(Some(Box::from(coin)), Ordering::Relaxed);
+        self.send(MinerCommand::Coin(coin));
     }
 
     fn update_difficulty(&self, difficulty : u64) {
-        self.tsdata.difficulty.replace(Some(Box::from(difficulty)), Ordering::Relaxed);
+        self.send(MinerCommand::Difficulty(difficulty));
     }
 
     fn is_stopped(&self) -> bool {
-        self.tsdata.should_stop.load(Ordering::Relaxed)
+        self.stopped.load(Ordering::Relaxed)
     }
 
     fn stop(&mut self) -> Result<(), Error> {
-        self.tsdata.should_stop.store(true, Ordering::Relaxed);
+        self.send(MinerCommand::Stop);
         if let Some(thread) = self.thread.take() {
             Ok(thread.join().unwrap()?)
         } else {
@@ -418,3 +572,47 @@ impl<T: MinerFunction + Sized + Send + 'static> Miner<T>
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_miner_keeps_running_ok() {
+        let (stats_schan, _stats_rchan) = mpsc::sync_channel(64);
+        let (coins_schan, coins_rchan) = mpsc::sync_channel(64);
+        let timeout = Duration::from_secs(10);
+
+        let mut miner = Miner::new(CpuMinerFunction::new(), MinerParams {
+            stats_schan,
+            coin_schan : coins_schan,
+            previous_coin : "a".repeat(cpen442coin::MD5_HASH_HEX_LEN),
+            difficulty : 1,
+            miner_id : "b".repeat(cpen442coin::MD5_HASH_HEX_LEN),
+            sched : ThreadSched::default(),
+        });
+
+        miner.run();
+
+        // Several finds on the same thread
+        for _ in 0..3 {
+            assert_eq!(coins_rchan.recv_timeout(timeout).unwrap().previous_coin,
+                "a".repeat(cpen442coin::MD5_HASH_HEX_LEN));
+        }
+        assert!(! miner.is_stopped());
+
+        let new_coin = "c".repeat(cpen442coin::MD5_HASH_HEX_LEN);
+        miner.update_prev_coin(new_coin.clone());
+
+        while coins_rchan.recv_timeout(timeout).unwrap().previous_coin != new_coin {}
+
+        // Keep taking coins so the miner isn't stuck sending one when told to stop
+        miner.send(MinerCommand::Stop);
+        while ! miner.is_stopped() {
+            while let Ok(_) = coins_rchan.try_recv() {}
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        miner.stop().unwrap();
+    }
+}
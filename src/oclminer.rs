@@ -2,40 +2,139 @@ use ocl;
 use openssl;
 use ocl_extras::full_device_info::FullDeviceInfo;
 use rand::{RngCore, rngs::OsRng};
+use serde::{Serialize, Deserialize};
 use crate::{
     ocldevice,
+    md5,
     error::Error,
     cpen442coin, cpen442coin::COIN_PREFIX_STR,
-    miner::{Coin, Stats, Miner, MinerFunction, MinerThreadData, MinerSharedData},
+    miner::{Coin, Stats, Miner, MinerFunction, MinerThreadData},
     util::*
 };
 use std::mem::size_of;
-use std::sync::{Arc, atomic::Ordering};
+use std::sync::{Arc, Mutex};
+use std::collections::VecDeque;
 use std::time::{Instant, Duration};
 use std::thread;
 
 pub type OclMiner = Miner<OclMinerFunction>;
 
+/// Versions of the MD5 kernel
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum KernelVariant {
+    /// Checks every hash after all of its steps
+    Baseline,
+    /// Hashes 4 counters at once with uint4 vectors
+    Vec4,
+    /// Rejects hashes that can't be coins a few steps before the end
+    EarlyReject,
+}
+
+impl KernelVariant {
+    pub const ALL : [KernelVariant; 3] = [
+        KernelVariant::Baseline,
+        KernelVariant::Vec4,
+        KernelVariant::EarlyReject,
+    ];
+
+    fn defines(self) -> &'static str {
+        match self {
+            KernelVariant::Baseline => "#define EARLY_REJECT 0\n#define VECTOR_WIDTH 1\n",
+            KernelVariant::Vec4 => "#define EARLY_REJECT 0\n#define VECTOR_WIDTH 4\n",
+            KernelVariant::EarlyReject => "#define EARLY_REJECT 1\n#define VECTOR_WIDTH 1\n",
+        }
+    }
+}
+
+/// Kernel settings that can be tuned for a device
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KernelConfig {
+    pub variant : KernelVariant,
+    /// Outer loops per work-item (N_LOOPS)
+    pub n_loops : u32,
+    /// Inner loops per outer loop (N_LOOPS_2)
+    pub n_loops_2 : u32,
+    /// Global work size as a multiple of the device's max workgroup size
+    pub wg_multiplier : usize,
+}
+
+impl Default for KernelConfig {
+    fn default() -> Self {
+        Self {
+            variant : KernelVariant::EarlyReject,
+            n_loops : 64,
+            n_loops_2 : 256,
+            wg_multiplier : 1,
+        }
+    }
+}
+
+impl KernelConfig {
+    /// True if other is the same kernel program, only launched differently
+    fn same_program(&self, other : &KernelConfig) -> bool {
+        self.variant == other.variant
+            && self.n_loops == other.n_loops
+            && self.n_loops_2 == other.n_loops_2
+    }
+}
+
+/// Workgroup multiplier search and loop time of a device, kept between
+/// miners so a new miner on the device carries on where the last one stopped
+#[derive(Clone)]
+struct WgSearchState {
+    wg_multiplier : usize,
+    found_peak : bool,
+    found_peak_time : Instant,
+    last_hash_rate : u64,
+    loop_ms : u64,
+}
+
+impl WgSearchState {
+    fn new(config : &KernelConfig) -> Self {
+        Self {
+            wg_multiplier : config.wg_multiplier,
+            found_peak : false,
+            found_peak_time : Instant::now(),
+            last_hash_rate : 0,
+            loop_ms : 0,
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct OclMinerFunction {
     context : ocl::Context,
     program : ocl::Program,
     device : ocl::Device,
+    config : KernelConfig,
     max_loop_ms : u32,
-    throttle_of_100 : u32
+    throttle_of_100 : u32,
+    realloc_buffers : bool,
+    /// Shared by the clones of this miner function
+    wg_search : Arc<Mutex<WgSearchState>>,
 }
 
 const DEBUG_ENABLE : usize = 0; // 0 or 1
 
 const OCL_WORD_LEN : usize = size_of::<u32>();
 const OCL_MESSAGE_LEN : usize = cpen442coin::MD5_BLOCK_LEN * 4;
-const OCL_BLOB_INDEX : usize = 5 * 4 + cpen442coin::MD5_HASH_HEX_LEN;
+// The first block is left alone by the kernel so its MD5 midstate is done on the host
+const OCL_MIDSTATE_BLOCKS : usize = 1;
+const OCL_BLOB_INDEX : usize = OCL_MIDSTATE_BLOCKS * cpen442coin::MD5_BLOCK_LEN;
 const OCL_BLOB_LEN_FAST : usize = 32 * 4;
 // Message: ["CPEN 442..." | PREV_COIN | BLOB | TRACKER]
 const OCL_BLOB_LEN : usize = OCL_MESSAGE_LEN - cpen442coin::MD5_HASH_HEX_LEN - OCL_BLOB_INDEX;
 const OCL_COUNTER_INDEX : usize = cpen442coin::MD5_BLOCK_LEN * 3 + OCL_WORD_LEN;
-const OCL_N_LOOPS : u32 = 64;
-const OCL_N_LOOPS_2 : u32 = 256;
+// Word 2 mask, 3 random values then the midstate
+const OCL_PARAMS_IN_LEN : usize = 8;
+// Hits kept per kernel launch, more are counted but dropped
+const OCL_MAX_RESULTS : usize = 16;
+// Hit count then (id, i, j) for each hit
+const OCL_RESULTS_LEN : usize = 1 + 3 * OCL_MAX_RESULTS;
+const OCL_PARAMS_LEN : usize = OCL_RESULTS_LEN +
+    DEBUG_ENABLE * (OCL_MESSAGE_LEN + cpen442coin::MD5_HASH_LEN);
+// Number of kernel launches queued on the device at once
+const OCL_PIPELINE_DEPTH : usize = 3;
 const MD5PROGRAM : &str = include_str!("cl/MD5.cl");
 
 // Same transformation as happens on the GPU
@@ -81,6 +180,19 @@ fn message_for_id(message_base: &[u8], mod_start: usize, mod_end: usize,
     message
 }
 
+/// The (id, i, j) of every hit kept in a launch's output
+/// and how many more hits there were that didn't fit
+fn launch_hits(params_out : &[u32]) -> (Vec<[u32; 3]>, usize) {
+    let count = params_out[0] as usize;
+    let kept = std::cmp::min(count, OCL_MAX_RESULTS);
+
+    let hits = params_out[1..1 + 3 * kept].chunks(3)
+        .map(|h| [h[0], h[1], h[2]])
+        .collect();
+
+    (hits, count - kept)
+}
+
 impl OclMinerFunction {
     pub fn new(platform : ocl::Platform, device : ocl::Device) -> Result<Self, Error> {
         let context = ocl::Context::builder()
@@ -88,6 +200,58 @@ impl OclMinerFunction {
             .devices(&device)
             .build()?;
 
+        let config = KernelConfig::default();
+        let program = Self::build_program(&context, &device, &config)?;
+        let wg_search = Arc::new(Mutex::new(WgSearchState::new(&config)));
+
+        Ok(Self {
+            context,
+            program,
+            device,
+            config,
+            wg_search,
+            max_loop_ms: 500,
+            throttle_of_100: 0,
+            realloc_buffers: false,
+        })
+    }
+
+    /// The same miner with other kernel settings, the kernel is only rebuilt if needed
+    pub fn with_config(&self, config : KernelConfig) -> Result<Self, Error> {
+        let program = if config.same_program(&self.config) {
+            self.program.clone()
+        } else {
+            Self::build_program(&self.context, &self.device, &config)?
+        };
+
+        Ok(Self {
+            program,
+            config,
+            // What was learned with the old settings doesn't apply
+            wg_search : Arc::new(Mutex::new(WgSearchState::new(&config))),
+            ..self.clone()
+        })
+    }
+
+    pub fn config(&self) -> KernelConfig {
+        self.config
+    }
+
+    pub fn device_descriptor(&self) -> String {
+        ocldevice::get_device_descriptor(&self.device)
+    }
+
+    pub fn max_loop(&self) -> Duration {
+        Duration::from_millis(self.max_loop_ms as u64)
+    }
+
+    fn build_program(context : &ocl::Context, device : &ocl::Device, config : &KernelConfig)
+        -> Result<ocl::Program, Error> {
+        if config.n_loops == 0 || config.n_loops_2 == 0 || config.n_loops_2 % 4 != 0
+            || config.wg_multiplier == 0 {
+            return Err(Error::Msg(format!("Bad OpenCL kernel config {:?}", config)));
+        }
+
         let mut md5_program = format!("
 #define MESSAGE_LEN ({message_len})
 #define BLOB_INDEX ({blob_index})
@@ -96,39 +260,96 @@ impl OclMinerFunction {
 #define N_LOOPS ({n_loops})
 #define N_LOOPS_2 ({n_loops_2})
 #define LAST_ROUND_COUNTER_INDEX ({counter_index})
+#define MAX_RESULTS ({max_results})
+#define MIDSTATE_BLOCKS ({midstate_blocks})
 \n",
             message_len=OCL_MESSAGE_LEN / OCL_WORD_LEN,
             blob_index=OCL_BLOB_INDEX / OCL_WORD_LEN,
             blob_len_fast=OCL_BLOB_LEN_FAST / OCL_WORD_LEN,
             blob_len=OCL_BLOB_LEN / OCL_WORD_LEN,
-            n_loops=OCL_N_LOOPS,
-            n_loops_2=OCL_N_LOOPS_2,
-            counter_index=OCL_COUNTER_INDEX / OCL_WORD_LEN);
+            n_loops=config.n_loops,
+            n_loops_2=config.n_loops_2,
+            counter_index=OCL_COUNTER_INDEX / OCL_WORD_LEN,
+            max_results=OCL_MAX_RESULTS,
+            midstate_blocks=OCL_MIDSTATE_BLOCKS);
 
         if DEBUG_ENABLE > 0 {
             md5_program += "\n\n#define __DEBUG_MODE__\n\n";
         }
 
+        md5_program += config.variant.defines();
         md5_program += MD5PROGRAM;
 
-        let program = ocl::Program::builder()
-            .devices(&device)
+        Ok(ocl::Program::builder()
+            .devices(device)
             .src(md5_program)
-            .build(&context)?;
-
-        Ok(Self {
-            context,
-            program,
-            device,
-            max_loop_ms: 500,
-            throttle_of_100: 0,
-        })
+            .build(context)?)
     }
 
     pub fn set_max_loop_ms(&mut self, loop_ms : u32) {
         self.max_loop_ms = loop_ms;
     }
 
+    /// Allocate new buffers for every kernel launch instead of reusing them,
+    /// only used to benchmark the difference
+    pub fn set_realloc_buffers(&mut self, realloc : bool) {
+        self.realloc_buffers = realloc;
+    }
+
+    fn hashes_per_launch(&self, wg_size : usize) -> u64 {
+        (self.config.n_loops as u64) * (self.config.n_loops_2 as u64) * wg_size as u64
+    }
+
+    /// Run the kernel back to back for about the duration without looking for
+    /// coins, returns the hash rate and the average time of a launch
+    pub fn measure(&self, duration : Duration) -> Result<(f64, Duration), Error> {
+        let wg_size = self.device.max_wg_size()? * self.config.wg_multiplier;
+
+        let mut launches = VecDeque::with_capacity(OCL_PIPELINE_DEPTH);
+        for _ in 0..OCL_PIPELINE_DEPTH {
+            let mut launch = OclLaunch::new(self)?;
+
+            OsRng.fill_bytes(&mut launch.message);
+            launch.params_in.iter_mut().for_each(|p| *p = OsRng.next_u32());
+            // Every bit of word 2 has to be 0 as well, there won't be any hits
+            launch.params_in[0] = 0xFFFFFFFF;
+            launch.wg_size = wg_size;
+
+            launch.enqueue()?;
+            launches.push_back(launch);
+        }
+
+        let mut start = Instant::now();
+        let mut n_launches = 0;
+        let mut warmed_up = false;
+
+        while n_launches == 0 || start.elapsed() < duration {
+            let mut launch = launches.pop_front().unwrap();
+            launch.finish()?;
+
+            // The first launch warms up the device and isn't counted
+            if warmed_up {
+                n_launches += 1;
+            } else {
+                warmed_up = true;
+                start = Instant::now();
+            }
+
+            launch.enqueue()?;
+            launches.push_back(launch);
+        }
+
+        let elapsed = start.elapsed();
+
+        for mut launch in launches {
+            launch.finish()?;
+        }
+
+        let hash_rate = (n_launches as u64 * self.hashes_per_launch(wg_size)) as f64 / elapsed.as_secs_f64();
+
+        Ok((hash_rate, elapsed / n_launches))
+    }
+
     pub fn throttle(&mut self, utilization : f32) -> Result<(), Error> {
         if 0.0 <= utilization && utilization <= 1.0 {
             self.throttle_of_100 = (100.0 * (1.0 - utilization)) as u32;
@@ -142,19 +363,143 @@ impl OclMinerFunction {
     }
 }
 
+/// A kernel launch with its own queue and buffers so several can be queued
+/// at once, the device works on the next launches while the host checks this one.
+/// The buffers are allocated once and rewritten for every launch.
+struct OclLaunch {
+    queue : ocl::Queue,
+    kernel : ocl::Kernel,
+    msg_buf : ocl::Buffer<u32>,
+    params_in_buf : ocl::Buffer<u32>,
+    params_out_buf : ocl::Buffer<u32>,
+    event : ocl::Event,
+    // What the kernel was launched with, needed to rebuild the message of a hit
+    message : [u8; OCL_MESSAGE_LEN],
+    params_in : [u32; OCL_PARAMS_IN_LEN],
+    params_out : [u32; OCL_PARAMS_LEN],
+    previous_coin : String,
+    /// Difficulty the launch searches for, its hits are reported at it
+    num_zeros : u64,
+    wg_size : usize,
+}
+
+impl OclLaunch {
+    fn new(ocl_fn : &OclMinerFunction) -> Result<Self, Error> {
+        let queue = ocl::Queue::new(&ocl_fn.context, ocl_fn.device.clone(), None)?;
+
+        let (msg_buf, params_in_buf, params_out_buf) = Self::buffers(&queue)?;
+
+        let kernel = ocl::Kernel::builder()
+            .program(&ocl_fn.program)
+            .name("md5")
+            .queue(queue.clone())
+            .arg(&msg_buf)
+            .arg(&params_in_buf)
+            .arg(&params_out_buf)
+            .build()?;
+
+        Ok(Self {
+            queue,
+            kernel,
+            msg_buf,
+            params_in_buf,
+            params_out_buf,
+            event : ocl::Event::empty(),
+            message : [0u8; OCL_MESSAGE_LEN],
+            params_in : [0u32; OCL_PARAMS_IN_LEN],
+            params_out : [0u32; OCL_PARAMS_LEN],
+            previous_coin : String::new(),
+            num_zeros : 0,
+            wg_size : 0,
+        })
+    }
+
+    fn buffers(queue : &ocl::Queue)
+        -> Result<(ocl::Buffer<u32>, ocl::Buffer<u32>, ocl::Buffer<u32>), Error> {
+        let msg_buf = ocl::Buffer::<u32>::builder()
+            .queue(queue.clone())
+            .flags(ocl::flags::MEM_READ_ONLY)
+            .len(OCL_MESSAGE_LEN / OCL_WORD_LEN)
+            .build()?;
+
+        let params_in_buf = ocl::Buffer::<u32>::builder()
+            .queue(queue.clone())
+            .flags(ocl::flags::MEM_READ_ONLY)
+            .len(OCL_PARAMS_IN_LEN)
+            .build()?;
+
+        let params_out_buf = ocl::Buffer::<u32>::builder()
+            .queue(queue.clone())
+            .flags(ocl::flags::MEM_READ_WRITE)
+            .len(OCL_PARAMS_LEN)
+            .build()?;
+
+        Ok((msg_buf, params_in_buf, params_out_buf))
+    }
+
+    /// Replace the buffers with newly allocated ones
+    fn realloc(&mut self) -> Result<(), Error> {
+        let (msg_buf, params_in_buf, params_out_buf) = Self::buffers(&self.queue)?;
+
+        self.kernel.set_arg(0, &msg_buf)?;
+        self.kernel.set_arg(1, &params_in_buf)?;
+        self.kernel.set_arg(2, &params_out_buf)?;
+
+        self.msg_buf = msg_buf;
+        self.params_in_buf = params_in_buf;
+        self.params_out_buf = params_out_buf;
+
+        Ok(())
+    }
+
+    /// Write the message and params and queue the kernel without waiting for it
+    fn enqueue(&mut self) -> Result<(), Error> {
+        let message_words : Vec<u32> = self.message.chunks(OCL_WORD_LEN)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+
+        self.params_out = [0u32; OCL_PARAMS_LEN];
+
+        self.msg_buf.write(&message_words).enq()?;
+        self.params_in_buf.write(&self.params_in[..]).enq()?;
+        self.params_out_buf.write(&self.params_out[..]).enq()?;
+
+        self.event = ocl::Event::empty();
+
+        unsafe {
+            self.kernel.cmd()
+                .global_work_size(self.wg_size)
+                .enew(&mut self.event)
+                .enq()?;
+        }
+
+        self.queue.flush()?;
+
+        Ok(())
+    }
+
+    /// Wait for the kernel to finish and read back its results
+    fn finish(&mut self) -> Result<(), Error> {
+        self.event.wait_for().map_err(ocl::Error::from)?;
+
+        self.params_out_buf.read(&mut self.params_out[..]).enq()?;
+
+        Ok(())
+    }
+}
+
 impl MinerFunction for OclMinerFunction {
-    fn run(self, tdata : MinerThreadData, tsdata: Arc<MinerSharedData>) -> Result<(), Error> {
+    fn run(self, tdata : MinerThreadData) -> Result<(), Error> {
         let mut rng = rand::thread_rng();
 
         let start = Instant::now();
         let device_descriptor = ocldevice::get_device_descriptor(&self.device);
         let mut stat_hash_counter = 0;
-        let mut loop_ms = 0;
         let mut loop_iterations = 0;
         let mut last_report_timer = Timer::new(Duration::from_millis(2000));
         let mut print_ocl_info_timer = Timer::new(Duration::from_secs(30));
-        let mut previous_coin = tsdata.previous_coin.take(Ordering::Relaxed).unwrap();
-        let mut num_zeros = *tsdata.difficulty.take(Ordering::Relaxed).unwrap();
+        let mut previous_coin = tdata.previous_coin.clone();
+        let mut num_zeros = tdata.difficulty;
 
         let num_zeros_to_word2_mask = |nz : u64| -> u32 {
             let even_nz = nz - (nz % 2);
@@ -168,8 +513,6 @@ impl MinerFunction for OclMinerFunction {
             }
         };
 
-        let mut hash_word2_mask = num_zeros_to_word2_mask(num_zeros);
-
         let mut message = [0u8; OCL_MESSAGE_LEN];
         let modifiable_start = COIN_PREFIX_STR.len() + previous_coin.len();
         let modifiable_end = message.len() - tdata.miner_id.len();
@@ -185,161 +528,150 @@ impl MinerFunction for OclMinerFunction {
 
         const WG_MULTIPLIER_CHANGE_ITERATIONS : u64 = 64;
         let dev_wg_size = self.device.max_wg_size()?;
-        let mut last_wg_multiplier_hash_rate = 0;
+        let wg_search = self.wg_search.lock().unwrap().clone();
+        let mut last_wg_multiplier_hash_rate = wg_search.last_hash_rate;
         let mut wg_multiplier_hash_count = 1;
         let mut wg_multiplier_runtime_ms = 1;
-        let mut wg_multiplier = 1;
-        let mut wg_multiplier_found_peak = false;
-        let mut wg_found_peak_time = Instant::now();
-
-        let queue = ocl::Queue::new(&self.context, self.device.clone(), None)?;
-
-        let kernel = ocl::Kernel::builder()
-            .program(&self.program)
-            .name("md5")
-            .queue(queue.clone())
-            .global_work_size(dev_wg_size)
-            .arg_named("base_message", None::<&ocl::Buffer<u32>>)
-            .arg_named("params_in", None::<&ocl::Buffer<u32>>)
-            .arg_named("params_out", None::<&ocl::Buffer<u32>>)
-            .build()?;
-
-        while ! tsdata.should_stop.load(Ordering::Relaxed) {
-            let loop_start = Instant::now();
-
-            // Current workgroup size
-            let wg_size = dev_wg_size * wg_multiplier;
-
-            // Generate a random base message
-            {
-                let mut i = modifiable_start;
-                // Add timestamp
-                let elapsed = (start.elapsed().as_nanos() + (OsRng.next_u64() as u128) << 64).to_ne_bytes();
-                message[i..i+elapsed[..].len()].copy_from_slice(&elapsed);
-                i += elapsed[..].len();
-
-                // openssl RNG
-                openssl::rand::rand_bytes(&mut message[i..i + 16]).unwrap();
-                i += 16;
-
-                // OS RNG
-                OsRng.fill_bytes(&mut message[i..i + 16]);
-                i += 16;
-
-                // rand RNG
-                rng.fill_bytes(&mut message[i..modifiable_end]);
-            }
-
-            let message_words : &[u32; OCL_MESSAGE_LEN / OCL_WORD_LEN] = 
-                unsafe { std::mem::transmute(&message) };
-
-            let msg_buf = ocl::Buffer::<u32>::builder()
-                .queue(queue.clone())
-                .flags(ocl::flags::MEM_READ_ONLY)
-                .len(OCL_MESSAGE_LEN / OCL_WORD_LEN)
-                .copy_host_slice(message_words)
-                .build()?;
-
-            let params_in = [
-                hash_word2_mask,
+        let mut wg_multiplier = wg_search.wg_multiplier;
+        let mut wg_multiplier_found_peak = wg_search.found_peak;
+        let mut wg_found_peak_time = wg_search.found_peak_time;
+        let mut loop_ms = wg_search.loop_ms;
+
+        // Fill in a launch with a new random message based on the current coin
+        let mut prepare_launch = |launch : &mut OclLaunch, message : &[u8; OCL_MESSAGE_LEN],
+            previous_coin : &str, num_zeros : u64, wg_size : usize| {
+            launch.message = *message;
+
+            let mut i = modifiable_start;
+            // Add timestamp
+            let elapsed = (start.elapsed().as_nanos() + (OsRng.next_u64() as u128) << 64).to_ne_bytes();
+            launch.message[i..i+elapsed[..].len()].copy_from_slice(&elapsed);
+            i += elapsed[..].len();
+
+            // openssl RNG
+            openssl::rand::rand_bytes(&mut launch.message[i..i + 16]).unwrap();
+            i += 16;
+
+            // OS RNG
+            OsRng.fill_bytes(&mut launch.message[i..i + 16]);
+            i += 16;
+
+            // rand RNG
+            rng.fill_bytes(&mut launch.message[i..modifiable_end]);
+
+            let midstate = md5::md5_midstate(
+                &launch.message[..OCL_MIDSTATE_BLOCKS * cpen442coin::MD5_BLOCK_LEN]);
+
+            launch.params_in = [
+                num_zeros_to_word2_mask(num_zeros),
                 OsRng.next_u32(),
                 rng.next_u32(),
                 OsRng.next_u32(),
+                midstate[0],
+                midstate[1],
+                midstate[2],
+                midstate[3],
             ];
 
-            let params_in_buf = ocl::Buffer::<u32>::builder()
-                .queue(queue.clone())
-                .flags(ocl::flags::MEM_READ_ONLY)
-                .len(4)
-                .copy_host_slice(&params_in)
-                .build()?;
+            launch.previous_coin = String::from(previous_coin);
+            launch.num_zeros = num_zeros;
+            launch.wg_size = wg_size;
+        };
 
-            const OCL_PARAMS_LEN : usize = 4 +
-                DEBUG_ENABLE * (OCL_MESSAGE_LEN + cpen442coin::MD5_HASH_LEN);
+        let mut launches = VecDeque::with_capacity(OCL_PIPELINE_DEPTH);
+        for _ in 0..OCL_PIPELINE_DEPTH {
+            let mut launch = OclLaunch::new(&self)?;
 
-            let mut params_out = [0xFFFFFFFFu32; OCL_PARAMS_LEN];
+            prepare_launch(&mut launch, &message, &previous_coin, num_zeros,
+                dev_wg_size * wg_multiplier);
+            launch.enqueue()?;
 
-            let params_out_buf = ocl::Buffer::<u32>::builder()
-                .queue(queue.clone())
-                .flags(ocl::flags::MEM_READ_WRITE)
-                .len(OCL_PARAMS_LEN)
-                .copy_host_slice(&params_out[..])
-                .build()?;
+            launches.push_back(launch);
+        }
 
-            kernel.set_arg("base_message", &msg_buf)?;
-            kernel.set_arg("params_in", &params_in_buf)?;
-            kernel.set_arg("params_out", &params_out_buf)?;
+        let mut last_finish = Instant::now();
 
-            let cmd = kernel.cmd()
-                .queue(&queue)
-                .global_work_size(wg_size);
+        // Throttled launches wait here until the queue is empty so the device idles while sleeping
+        let mut parked = Vec::with_capacity(OCL_PIPELINE_DEPTH);
+        let mut throttled_iterations = 0;
 
-            unsafe { cmd.enq()?; }
+        let mut stop = false;
 
-            params_out_buf.cmd()
-                .queue(&queue)
-                .offset(0)
-                .read(&mut params_out[..])
-                .enq()?;
+        while ! stop {
+            // Oldest launch first, the others keep the device busy meanwhile
+            let mut launch = launches.pop_front().unwrap();
+            launch.finish()?;
 
-            queue.finish()?;
-
-            let loop_iteration_ms = loop_start.elapsed().as_millis() as u64;
+            // Time between launches finishing is how long each takes on the device
+            let loop_iteration_ms = last_finish.elapsed().as_millis() as u64;
+            last_finish = Instant::now();
             loop_ms = (loop_ms + loop_iteration_ms) / 2;
             wg_multiplier_runtime_ms += loop_iteration_ms;
 
-            // Check if the coin was updated, if so then the result is invalidated
-            if let Some(new_coin) = tsdata.previous_coin.take(Ordering::Relaxed) {
+            let update = tdata.updates();
+            stop = update.stop;
+
+            // Check if the coin was updated, if so then results for the old coin are invalidated
+            if let Some(new_coin) = update.previous_coin {
                 previous_coin = new_coin;
                 message[COIN_PREFIX_STR.len()..modifiable_start].copy_from_slice(previous_coin.as_bytes());
-                continue;
             }
 
-            // Check if difficulty was updated, if so then the result **might** be invalidated
-            if let Some(new_num_zeros) = tsdata.difficulty.take(Ordering::Relaxed) {
-                num_zeros = *new_num_zeros;
-                hash_word2_mask = num_zeros_to_word2_mask(num_zeros);
+            // Check if difficulty was updated, launches from before it keep reporting the old one
+            // so the manager can drop their hits that are too easy
+            if let Some(new_num_zeros) = update.difficulty {
+                num_zeros = new_num_zeros;
+            }
+
+            let params_out = &launch.params_out;
+            let (hits, dropped) = launch_hits(params_out);
+
+            if dropped > 0 {
+                println!("\nOpenCL ({}) Dropped {} hits, only {} fit in a launch",
+                    device_descriptor, dropped, OCL_MAX_RESULTS);
             }
 
-            if params_out[0] != 0xFFFFFFFF {
+            if ! hits.is_empty() && launch.previous_coin == previous_coin {
                 if DEBUG_ENABLE > 0 {
                     let mut hash = Vec::new();
 
-                    for i in 3..=6 {
+                    for i in OCL_RESULTS_LEN..OCL_RESULTS_LEN+4 {
                         hash.extend_from_slice(&params_out[i].to_le_bytes());
                     }
 
-                    println!("\nDEBUG Word 2 Mask: {:?}\n", hash_word2_mask.to_le_bytes());
+                    println!("\nDEBUG Word 2 Mask: {:?}\n", launch.params_in[0].to_le_bytes());
                     println!("\nDEBUG GPU Hash: {}\n", hex::encode(hash));
 
                     let mut gpu_message = Vec::new();
 
-                    for i in 7..7+(OCL_MESSAGE_LEN / OCL_WORD_LEN) {
+                    for i in OCL_RESULTS_LEN+4..OCL_RESULTS_LEN+4+(OCL_MESSAGE_LEN / OCL_WORD_LEN) {
                         gpu_message.extend_from_slice(&params_out[i].to_le_bytes());
                     }
 
                     println!("\nDEBUG GPU Message: {}\n", hex::encode(gpu_message));
-                    println!("\nDEBUG Base Message: {}\n", hex::encode(&message[..]));
+                    println!("\nDEBUG Base Message: {}\n", hex::encode(&launch.message[..]));
                 }
 
-                let coin = Coin {
-                    previous_coin : (*previous_coin).clone(),
-                    num_zeros,
-                    blob : message_for_id(&message, modifiable_start, modifiable_end,
-                        params_out[0], params_out[1], params_out[2], &params_in[1..4]),
-                };
-
-                match tdata.coin_schan.send(coin) {
-                    Ok(_) => {},
-                    Err(e) => return Err(Error::new(
-                            format!("Miner Mpsc Send Failed: {:?}", e)))
-                };
+                for hit in hits {
+                    let coin = Coin {
+                        previous_coin : launch.previous_coin.clone(),
+                        num_zeros : launch.num_zeros,
+                        blob : message_for_id(&launch.message, modifiable_start, modifiable_end,
+                            hit[0], hit[1], hit[2], &launch.params_in[1..4]),
+                    };
+
+                    match tdata.coin_schan.send(coin) {
+                        Ok(_) => {},
+                        Err(e) => return Err(Error::new(
+                                format!("Miner Mpsc Send Failed: {:?}", e)))
+                    };
+                }
             }
 
             loop_iterations += 1;
 
             // Gather hashes for stats
-            let nhashes = (OCL_N_LOOPS as u64) * (OCL_N_LOOPS_2 as u64) * wg_size as u64;
+            let nhashes = self.hashes_per_launch(launch.wg_size);
             stat_hash_counter += nhashes;
             wg_multiplier_hash_count += nhashes;
 
@@ -352,11 +684,11 @@ impl MinerFunction for OclMinerFunction {
 
             if print_ocl_info_timer.check_and_reset() {
                 println!("\nOpenCL ({}) Workgroup Size: {}, Workgroup Multiplier: {}, Loop Time: {} ms",
-                    device_descriptor, wg_size, wg_multiplier, loop_ms);
+                    device_descriptor, launch.wg_size, wg_multiplier, loop_ms);
             }
 
             if loop_iterations % 100 < self.throttle_of_100 as u64 {
-                thread::sleep(Duration::from_millis(2 * loop_ms));
+                throttled_iterations += 1;
             }
 
             if loop_iterations % WG_MULTIPLIER_CHANGE_ITERATIONS == 0 {
@@ -365,12 +697,12 @@ impl MinerFunction for OclMinerFunction {
                 if ! wg_multiplier_found_peak {
                     // Loop is taking too long
                     if cur_loop_ms > self.max_loop_ms as u64 {
-                        wg_multiplier /= 2;
+                        wg_multiplier = std::cmp::max(wg_multiplier / 2, 1);
                         wg_multiplier_found_peak = true;
                         wg_found_peak_time = Instant::now();
                     } else if hash_rate < last_wg_multiplier_hash_rate {
                         // Increased wg size decreased performance
-                        wg_multiplier /= 2;
+                        wg_multiplier = std::cmp::max(wg_multiplier / 2, 1);
                         wg_multiplier_found_peak = true;
                         wg_found_peak_time = Instant::now();
                     } else {
@@ -385,9 +717,49 @@ impl MinerFunction for OclMinerFunction {
                 last_wg_multiplier_hash_rate = hash_rate;
                 wg_multiplier_hash_count = 0;
                 wg_multiplier_runtime_ms = 0;
+
+                *self.wg_search.lock().unwrap() = WgSearchState {
+                    wg_multiplier,
+                    found_peak : wg_multiplier_found_peak,
+                    found_peak_time : wg_found_peak_time,
+                    last_hash_rate : last_wg_multiplier_hash_rate,
+                    loop_ms,
+                };
+            }
+
+            // Queue the launch again with a new message
+            prepare_launch(&mut launch, &message, &previous_coin, num_zeros,
+                dev_wg_size * wg_multiplier);
+
+            if self.realloc_buffers {
+                launch.realloc()?;
+            }
+
+            if throttled_iterations > 0 {
+                parked.push(launch);
+            } else {
+                launch.enqueue()?;
+                launches.push_back(launch);
+            }
+
+            if launches.is_empty() && ! stop {
+                thread::sleep(Duration::from_millis(2 * loop_ms * throttled_iterations));
+                throttled_iterations = 0;
+
+                for mut launch in parked.drain(..) {
+                    launch.enqueue()?;
+                    launches.push_back(launch);
+                }
+
+                // The sleep isn't part of how long the launches take
+                last_finish = Instant::now();
             }
         }
 
+        for mut launch in launches {
+            launch.finish()?;
+        }
+
         Ok(())
     }
 }
@@ -402,3 +774,130 @@ fn to_big_endian_u32(buf : &mut [u8]) {
         buf[i..i+OCL_WORD_LEN].copy_from_slice(&val.to_be_bytes());
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_launch_hits_ok() {
+        let mut params_out = [0u32; OCL_PARAMS_LEN];
+        assert!(launch_hits(&params_out).0.is_empty());
+
+        params_out[0] = 2;
+        params_out[1..7].copy_from_slice(&[5, 1, 2, 9, 3, 4]);
+        assert_eq!(launch_hits(&params_out), (vec![[5, 1, 2], [9, 3, 4]], 0));
+
+        // More hits than fit in a launch
+        params_out[0] = OCL_MAX_RESULTS as u32 + 3;
+        let (hits, dropped) = launch_hits(&params_out);
+        assert_eq!(hits.len(), OCL_MAX_RESULTS);
+        assert_eq!(dropped, 3);
+    }
+
+    /// A STEP of MD5_STEPS in MD5.cl: function, registers a, b, c and d
+    /// as indexes, message word, constant and shift
+    type KernelStep = (char, [usize; 4], usize, u32, u32);
+
+    /// The steps of MD5_STEPS read from MD5.cl, and the number of steps before EARLY_CHECK
+    fn kernel_steps() -> (Vec<KernelStep>, usize) {
+        let start = MD5PROGRAM.find("#define MD5_STEPS").unwrap();
+        let end = start + MD5PROGRAM[start..].find("\n\n").unwrap();
+
+        let mut steps = Vec::new();
+        let mut early_check = None;
+
+        for line in MD5PROGRAM[start..end].lines().skip(1).map(|l| l.trim()) {
+            if line.starts_with("EARLY_CHECK") {
+                early_check = Some(steps.len());
+            } else if line.starts_with("STEP(") {
+                let args : Vec<&str> = line["STEP(".len()..line.rfind(')').unwrap()]
+                    .split(',').map(|a| a.trim()).collect();
+
+                let reg = |r : &str| "abcd".find(r).unwrap();
+                let word = args[5].trim_start_matches("GET(").trim_end_matches(')').parse().unwrap();
+
+                steps.push((args[0].chars().next().unwrap(),
+                    [reg(args[1]), reg(args[2]), reg(args[3]), reg(args[4])],
+                    word,
+                    u32::from_str_radix(args[6].trim_start_matches("0x"), 16).unwrap(),
+                    args[7].parse().unwrap()));
+            }
+        }
+
+        (steps, early_check.unwrap())
+    }
+
+    /// md5_round_ex of MD5.cl run step by step. The kernel rejects when the early first
+    /// word isn't 0, coins that rare can't be found here so it is compared with target instead.
+    fn kernel_round(steps : &[KernelStep], early_check : usize, internal_state : &mut [u32; 4],
+        message : &[u32], early_reject : Option<u32>) -> bool {
+        let mut r = *internal_state;
+
+        for (i, (f, [a, b, c, d], x, t, s)) in steps.iter().enumerate() {
+            if let Some(target) = early_reject {
+                if i == early_check && r[0].wrapping_add(internal_state[0]) != target {
+                    return false;
+                }
+            }
+
+            let (x_, y, z) = (r[*b], r[*c], r[*d]);
+            let fv = match f {
+                'F' => z ^ (x_ & (y ^ z)),
+                'G' => y ^ (z & (x_ ^ y)),
+                'H' => x_ ^ y ^ z,
+                _ => y ^ (x_ | !z),
+            };
+
+            r[*a] = r[*a].wrapping_add(fv).wrapping_add(message[*x]).wrapping_add(*t)
+                .rotate_left(*s).wrapping_add(r[*b]);
+        }
+
+        for (s, r) in internal_state.iter_mut().zip(r.iter()) {
+            *s = s.wrapping_add(*r);
+        }
+
+        true
+    }
+
+    #[test]
+    fn test_kernel_early_reject_same_hits() {
+        use openssl::hash::{hash, MessageDigest};
+
+        let (steps, early_check) = kernel_steps();
+        assert_eq!(steps.len(), 64);
+
+        let mut zero_pad = [0u32; md5::MD5_BLOCK_WORDS];
+        zero_pad[0] = 0x80;
+        zero_pad[14] = (OCL_MESSAGE_LEN * 8) as u32;
+
+        for n in 0..2000 {
+            let mut message = [0u8; OCL_MESSAGE_LEN];
+            openssl::rand::rand_bytes(&mut message).unwrap();
+            let words : Vec<u32> = message.chunks(OCL_WORD_LEN)
+                .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+                .collect();
+
+            let mut state = md5::MD5_INIT;
+            for block in words.chunks(md5::MD5_BLOCK_WORDS) {
+                kernel_round(&steps, early_check, &mut state, block, None);
+            }
+            let last_block_state = state;
+
+            // The Baseline kernel's hash, checked against OpenSSL so the steps were read right
+            kernel_round(&steps, early_check, &mut state, &zero_pad, None);
+            let digest : Vec<u8> = state.iter().flat_map(|w| w.to_le_bytes().to_vec()).collect();
+            assert_eq!(digest, hash(MessageDigest::md5(), &message).unwrap().to_vec());
+
+            // Half of the hashes are hits, EarlyReject must keep those and only those
+            let target = if n % 2 == 0 { state[0] } else { OsRng.next_u32() };
+            let baseline_hit = state[0] == target;
+
+            let mut early_state = last_block_state;
+            let early_hit = kernel_round(&steps, early_check, &mut early_state, &zero_pad, Some(target))
+                && early_state[0] == target;
+
+            assert_eq!(early_hit, baseline_hit);
+        }
+    }
+}
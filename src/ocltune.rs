@@ -0,0 +1,142 @@
+//! OpenCL Autotuning
+//!
+//! Benchmarks every kernel variant with a range of loop counts and
+//! workgroup multipliers on a device and keeps the fastest settings
+//! in a tuning file, keyed by the device's descriptor.
+use crate::error::Error;
+use crate::oclminer::{OclMinerFunction, KernelConfig, KernelVariant};
+use serde::{Serialize, Deserialize};
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const N_LOOPS : [u32; 3] = [32, 64, 128];
+const N_LOOPS_2 : [u32; 3] = [128, 256, 512];
+const MAX_WG_MULTIPLIER : usize = 64;
+/// How long each configuration is run for
+const MEASURE_TIME : Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Default)]
+struct TuningState {
+    devices : BTreeMap<String, KernelConfig>,
+}
+
+/// Tuned kernel settings of each device
+pub struct Tuning {
+    filename : PathBuf,
+    state : TuningState,
+}
+
+impl Tuning {
+    /// Read the tuning file, a missing file is the same as an empty one
+    pub fn load(filename : &Path) -> Result<Self, Error> {
+        let state = if filename.exists() {
+            serde_json::from_reader(File::open(filename)?)?
+        } else {
+            TuningState::default()
+        };
+
+        Ok(Self {
+            filename : filename.to_path_buf(),
+            state,
+        })
+    }
+
+    pub fn get(&self, device_descriptor : &str) -> Option<KernelConfig> {
+        self.state.devices.get(device_descriptor).cloned()
+    }
+
+    /// Save the settings of a device to the tuning file
+    pub fn set(&mut self, device_descriptor : &str, config : KernelConfig) -> Result<(), Error> {
+        self.state.devices.insert(String::from(device_descriptor), config);
+
+        serde_json::to_writer_pretty(File::create(&self.filename)?, &self.state)?;
+
+        Ok(())
+    }
+}
+
+/// Find the fastest kernel settings for the miner's device,
+/// launches that take longer than the miner's max loop time are not used
+pub fn autotune(oclf : &OclMinerFunction) -> Result<KernelConfig, Error> {
+    let descriptor = oclf.device_descriptor();
+    let mut best : Option<(KernelConfig, f64)> = None;
+
+    println!("Autotuning OpenCL ({})", descriptor);
+
+    for variant in KernelVariant::ALL.iter() {
+        for n_loops in N_LOOPS.iter() {
+            for n_loops_2 in N_LOOPS_2.iter() {
+                let mut last_hash_rate = 0.0;
+                let mut wg_multiplier = 1;
+
+                // Bigger workgroups until the hash rate stops going up
+                while wg_multiplier <= MAX_WG_MULTIPLIER {
+                    let config = KernelConfig {
+                        variant : *variant,
+                        n_loops : *n_loops,
+                        n_loops_2 : *n_loops_2,
+                        wg_multiplier,
+                    };
+
+                    let (hash_rate, loop_time) = oclf.with_config(config)?.measure(MEASURE_TIME)?;
+
+                    if loop_time > oclf.max_loop() {
+                        break;
+                    }
+
+                    println!("  {:?}: {:.2} MHash/s, Loop Time: {} ms",
+                        config, hash_rate / 1e6, loop_time.as_millis());
+
+                    if best.map(|(_, r)| hash_rate > r).unwrap_or(true) {
+                        best = Some((config, hash_rate));
+                    }
+
+                    if hash_rate < last_hash_rate {
+                        break;
+                    }
+
+                    last_hash_rate = hash_rate;
+                    wg_multiplier *= 2;
+                }
+            }
+        }
+    }
+
+    match best {
+        Some((config, hash_rate)) => {
+            println!("Best OpenCL ({}) Settings: {:?}, {:.2} MHash/s", descriptor, config, hash_rate / 1e6);
+            Ok(config)
+        },
+        None => Err(Error::Msg(format!(
+                    "No OpenCL ({}) kernel settings finished within the max loop time", descriptor))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tuning_file_ok() {
+        let filename = std::env::temp_dir().join(format!("cpen442-tuning-{}.json", std::process::id()));
+        let config = KernelConfig {
+            variant : KernelVariant::Vec4,
+            n_loops : 32,
+            n_loops_2 : 512,
+            wg_multiplier : 8,
+        };
+
+        let mut tuning = Tuning::load(&filename).unwrap();
+        assert!(tuning.get("Vendor GPU").is_none());
+        tuning.set("Vendor GPU", config).unwrap();
+
+        let tuning = Tuning::load(&filename).unwrap();
+        assert_eq!(tuning.get("Vendor GPU"), Some(config));
+        assert!(tuning.get("Other GPU").is_none());
+
+        std::fs::remove_file(filename).unwrap();
+    }
+}
@@ -0,0 +1,320 @@
+//! Pool Tracker
+//!
+//! Mines for a pool instead of claiming coins directly. The pool hands
+//! out the coin, difficulty and the miner id to hash coins with, and
+//! claims the coins found by its workers with the real tracker.
+//!
+//! The pool speaks JSON over HTTP:
+//!
+//! `POST {pool}/work` with `{"worker": ..}` returns
+//! `{"coin_id": .., "number_of_leading_zeros": .., "id_of_miner": ..}`
+//!
+//! `POST {pool}/submit` with `{"worker": .., "coin_blob": .., "hash_of_last_coin": ..}`
+//! returns `{"success": ..}` or `{"fail": ..}` like the tracker's claim_coin.
+//!
+//! Requests get the same faults, headers, rate limits and recording
+//! as direct requests to the tracker, getting work counts as last_coin.
+use base64;
+use reqwest::Client;
+use serde::{Serialize, Deserialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::error::Error;
+use crate::cpen442coin::{self, CoinTracker, HttpTracker, DIRECT, MD5_HASH_HEX_LEN};
+use crate::faults::{FaultInjector, TrackerOp};
+use crate::headers::HeaderPolicy;
+use crate::ratelimit::{RateLimiter, RateLimits};
+use crate::replay::Recorder;
+
+/// How long fetched work is used before asking the pool again,
+/// so a last coin and difficulty check share one request
+const WORK_MAX_AGE : Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct WorkReq<'a> {
+    worker : &'a str,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+struct PoolWork {
+    coin_id : String,
+    number_of_leading_zeros : u64,
+    id_of_miner : String,
+}
+
+#[derive(Serialize)]
+struct SubmitReq<'a> {
+    worker : &'a str,
+    coin_blob : String,
+    hash_of_last_coin : String,
+}
+
+pub struct PoolTracker {
+    worker : String,
+    work_url : String,
+    submit_url : String,
+    client : Client,
+    work : PoolWork,
+    work_time : Instant,
+    faults : FaultInjector,
+    headers : HeaderPolicy,
+    limiter : RateLimiter,
+    recorder : Option<Arc<Recorder>>,
+}
+
+impl PoolTracker {
+    /// Fails if the pool can't be reached, its miner id is needed before mining
+    pub fn new(worker : String, pool_url : &str, faults : FaultInjector,
+        headers : HeaderPolicy, limits : RateLimits) -> Result<PoolTracker, Error> {
+        let client = Client::builder()
+            .timeout(Duration::from_secs(10))
+            .gzip(false)
+            .build()?;
+
+        let pool_url = pool_url.trim_end_matches('/');
+
+        let mut tracker = PoolTracker {
+            worker,
+            work_url : format!("{}/work", pool_url),
+            submit_url : format!("{}/submit", pool_url),
+            client,
+            work : PoolWork { coin_id : String::new(), number_of_leading_zeros : 0, id_of_miner : String::new() },
+            work_time : Instant::now(),
+            faults,
+            headers,
+            limiter : RateLimiter::new(&limits),
+            recorder : None,
+        };
+
+        tracker.limiter.try_acquire(TrackerOp::LastCoin);
+        tracker.work = tracker.get_work()?;
+
+        println!("Pool Miner Id: {}", tracker.work.id_of_miner);
+
+        Ok(tracker)
+    }
+
+    fn get_work(&self) -> Result<PoolWork, Error> {
+        let injected = self.faults.inject(TrackerOp::LastCoin)?;
+
+        let request = self.headers.apply(self.client.post(&self.work_url))
+            .json(&WorkReq { worker : &self.worker });
+        let (code, body) = HttpTracker::send(request, &self.work_url, DIRECT, self.recorder.as_deref())?;
+        let body = injected.body(body);
+
+        if code.is_success() {
+            let work : PoolWork = serde_json::from_str(&body)?;
+
+            for hex_id in &[&work.coin_id, &work.id_of_miner] {
+                if hex_id.len() != MD5_HASH_HEX_LEN {
+                    return Err(Error::Msg(format!("Pool sent a bad id \"{}\"", hex_id)));
+                }
+                hex::decode(hex_id)?;
+            }
+
+            Ok(work)
+        } else if code.as_u16() == 429 || code.as_u16() == 409 {
+            Err(Error::ServerBusy)
+        } else {
+            Err(Error::new(format!("Get Pool Work Failed Http {}: {}",
+                        code.as_u16(), code.canonical_reason().unwrap_or(""))))
+        }
+    }
+
+    /// The latest work, asks the pool if it's older than WORK_MAX_AGE
+    /// and the rate limit allows it
+    fn work(&mut self) -> Result<&PoolWork, Error> {
+        if self.work_time.elapsed() >= WORK_MAX_AGE && self.limiter.try_acquire(TrackerOp::LastCoin) {
+            let work = self.get_work()?;
+
+            // Coins already being mined are hashed with the old id
+            if work.id_of_miner != self.work.id_of_miner {
+                return Err(Error::Msg(format!("Pool miner id changed from {} to {}, restart the miner",
+                            self.work.id_of_miner, work.id_of_miner)));
+            }
+
+            self.work = work;
+            self.work_time = Instant::now();
+        }
+
+        Ok(&self.work)
+    }
+}
+
+impl CoinTracker for PoolTracker {
+    /// Coins are hashed with the pool's miner id, not the worker's identity
+    fn id(&self) -> &str {
+        &self.work.id_of_miner
+    }
+
+    fn last_coin(&mut self) -> Result<String, Error> {
+        Ok(self.work()?.coin_id.clone())
+    }
+
+    fn difficulty(&mut self) -> Result<u64, Error> {
+        Ok(self.work()?.number_of_leading_zeros)
+    }
+
+    fn set_recorder(&mut self, recorder : Arc<Recorder>) {
+        self.recorder = Some(recorder);
+    }
+
+    fn claim(&mut self, blob : Vec<u8>, previous_coin : String, hash : &str) -> Result<(), Error> {
+        use reqwest::header::CONTENT_TYPE;
+
+        if ! self.limiter.try_acquire(TrackerOp::ClaimCoin) {
+            return Err(Error::new("Pool claim rate limit reached".into()));
+        }
+
+        let injected = self.faults.inject(TrackerOp::ClaimCoin)?;
+
+        let req = SubmitReq {
+            worker : &self.worker,
+            coin_blob : base64::encode(&blob),
+            hash_of_last_coin : previous_coin,
+        };
+
+        let request = self.headers.apply(self.client.post(&self.submit_url))
+            .header(CONTENT_TYPE, "application/json")
+            .json(&req);
+        let (code, body) = HttpTracker::send(request, &self.submit_url, DIRECT, self.recorder.as_deref())?;
+
+        cpen442coin::parse_claim(code, &injected.body(body))?;
+
+        // The claimed coin is the one to mine on next
+        self.work.coin_id = String::from(hash);
+        self.work_time = Instant::now();
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::faults::FaultRates;
+    use crate::headers::HeaderOpts;
+    use crate::replay::RecordingTracker;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answers each request with the next of the given bodies, returns the requests
+    fn spawn_pool(responses : Vec<(u16, &'static str)>) -> (String, thread::JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        (url, thread::spawn(move || {
+            let mut requests = Vec::new();
+
+            for (code, body) in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+
+                // Head then the Content-Length of body
+                let mut request = Vec::new();
+                let mut b = [0u8; 1];
+                while ! request.ends_with(b"\r\n\r\n") {
+                    stream.read_exact(&mut b).unwrap();
+                    request.push(b[0]);
+                }
+                let head = String::from_utf8(request.clone()).unwrap().to_lowercase();
+                let len = head.lines()
+                    .find(|l| l.starts_with("content-length:"))
+                    .map(|l| l["content-length:".len()..].trim().parse().unwrap())
+                    .unwrap_or(0);
+                let mut req_body = vec![0u8; len];
+                stream.read_exact(&mut req_body).unwrap();
+                request.extend(req_body);
+
+                write!(stream, "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    code, body.len(), body).unwrap();
+
+                requests.push(String::from_utf8(request).unwrap());
+            }
+
+            requests
+        }))
+    }
+
+    fn new_tracker(url : &str, busy_rate : f64, limits : &str) -> Result<PoolTracker, Error> {
+        let faults = FaultInjector::new(FaultRates {
+            server_busy : busy_rate, bad_coin : 0.0, timeout : 0.0, malformed : 0.0,
+        }).unwrap();
+        let headers = HeaderPolicy::new(HeaderOpts {
+            user_agents : vec!["Pool Agent".into()],
+            forwarded_for : false,
+            jitter : Duration::from_millis(0),
+        }).unwrap();
+
+        PoolTracker::new("worker-1".into(), url, faults, headers, limits.parse().unwrap())
+    }
+
+    const WORK : &str = r#"{"coin_id": "00000000008c70b237c12e2c25d278cc",
+        "number_of_leading_zeros": 9, "id_of_miner": "0123456789abcdef0123456789abcdef"}"#;
+
+    #[test]
+    fn test_pool_tracker_ok() {
+        let (url, pool) = spawn_pool(vec![
+            (200, WORK),
+            (200, r#"{"success": "ok"}"#),
+            (400, r#"{"fail": "too slow"}"#),
+        ]);
+
+        let mut tracker = new_tracker(&url, 0.0, "5,2,10").unwrap();
+        assert_eq!(tracker.id(), "0123456789abcdef0123456789abcdef");
+        assert_eq!(tracker.last_coin().unwrap(), "00000000008c70b237c12e2c25d278cc");
+        assert_eq!(tracker.difficulty().unwrap(), 9);
+
+        tracker.claim(vec![1, 2, 3], "00000000008c70b237c12e2c25d278cc".into(), "000000000abc").unwrap();
+        assert_eq!(tracker.last_coin().unwrap(), "000000000abc");
+        assert!(match tracker.claim(vec![], "".into(), "") { Err(Error::BadCoin(_)) => true, _ => false });
+
+        let requests = pool.join().unwrap();
+        assert!(requests[0].starts_with("POST /work "));
+        assert!(requests[0].ends_with(r#"{"worker":"worker-1"}"#));
+        assert!(requests[1].starts_with("POST /submit "));
+        assert!(requests[1].contains(r#""coin_blob":"AQID""#));
+    }
+
+    #[test]
+    fn test_pool_tracker_bad() {
+        let (url, pool) = spawn_pool(vec![(200, r#"{"coin_id": "00", "number_of_leading_zeros": 9,
+            "id_of_miner": "0123456789abcdef0123456789abcdef"}"#)]);
+
+        assert!(new_tracker(&url, 0.0, "5,2,10").is_err());
+        pool.join().unwrap();
+
+        // Injected faults fail before the pool is asked
+        let (url, pool) = spawn_pool(vec![]);
+        assert!(match new_tracker(&url, 1.0, "5,2,10") { Err(Error::ServerBusy) => true, _ => false });
+        pool.join().unwrap();
+    }
+
+    #[test]
+    fn test_pool_tracker_requests_ok() {
+        let (url, pool) = spawn_pool(vec![
+            (200, WORK),
+            (200, r#"{"success": "ok"}"#),
+        ]);
+        let path = std::env::temp_dir().join(format!("cpen442-pool-{}.jsonl", std::process::id()));
+
+        let tracker = new_tracker(&url, 0.0, "1,1,1").unwrap();
+        let mut tracker = RecordingTracker::new(tracker, path.clone()).unwrap();
+        tracker.start(1000);
+
+        // Rate limited requests use the work already fetched or fail without asking the pool
+        thread::sleep(WORK_MAX_AGE);
+        assert_eq!(tracker.last_coin().unwrap(), "00000000008c70b237c12e2c25d278cc");
+        tracker.claim(vec![1, 2, 3], "00000000008c70b237c12e2c25d278cc".into(), "000000000abc").unwrap();
+        assert!(tracker.claim(vec![1, 2, 3], "000000000abc".into(), "000000000def").is_err());
+
+        let requests = pool.join().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.to_lowercase().contains("\r\nuser-agent: pool agent\r\n")));
+
+        let recording = std::fs::read_to_string(&path).unwrap();
+        assert!(recording.contains(&format!(r#""op":"http","url":"{}submit""#, url)));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,179 @@
+//! Proxy Selection
+//!
+//! Picks which proxies to use with UCB1, balancing proxies that
+//! have worked well (fast and successful) against trying the others.
+use crate::faults::TrackerOp;
+use rand::{rngs::OsRng, seq::SliceRandom};
+use serde::{Serialize, Deserialize};
+use std::time::Duration;
+
+/// Kinds of traffic scored separately, a proxy that is good
+/// for polling isn't necessarily good for claiming coins
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Traffic {
+    /// Last coin and difficulty polling
+    Poll,
+    /// Coin claims
+    Claim,
+}
+
+impl Traffic {
+    pub fn of(op : TrackerOp) -> Self {
+        match op {
+            TrackerOp::LastCoin | TrackerOp::Difficulty => Traffic::Poll,
+            TrackerOp::ClaimCoin => Traffic::Claim,
+        }
+    }
+
+    /// How much to favour trying proxies with few uses, claims should
+    /// mostly go to proxies known to be good
+    fn exploration(self) -> f64 {
+        match self {
+            Traffic::Poll => 1.0,
+            Traffic::Claim => 0.25,
+        }
+    }
+}
+
+/// Selection statistics of a proxy for one kind of traffic
+#[derive(Clone, Default, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArmStats {
+    uses : u64,
+    /// Average reward [0 to 1], the last 10 or so uses count the most
+    mean_reward : f64,
+}
+
+impl ArmStats {
+    /// Stats of a single use worth reward, for proxies known from before the stats were kept
+    pub fn seeded(reward : f64) -> Self {
+        ArmStats {
+            uses : 1,
+            mean_reward : reward.max(0.0).min(1.0),
+        }
+    }
+
+    /// Expected reward of a proxy that succeeds success_ratio of the time taking latency
+    pub fn reward_of(success_ratio : f64, latency : Duration) -> f64 {
+        success_ratio / (1.0 + latency.as_secs_f64())
+    }
+
+    /// Record the result of a request, faster successful requests are worth more
+    pub fn update(&mut self, success : bool, latency : Duration) {
+        let reward = Self::reward_of(if success { 1.0 } else { 0.0 }, latency);
+
+        self.uses += 1;
+
+        // Plain average at first, then a rolling average so proxies can recover or go bad
+        let alpha = (1.0 / self.uses as f64).max(0.1);
+        self.mean_reward += alpha * (reward - self.mean_reward);
+    }
+
+    fn ucb(&self, total_uses : u64, exploration : f64) -> f64 {
+        if self.uses == 0 {
+            return std::f64::INFINITY;
+        }
+
+        let total = std::cmp::max(total_uses, 1) as f64;
+
+        self.mean_reward + exploration * (2.0 * total.ln() / self.uses as f64).sqrt()
+    }
+}
+
+/// Pick up to n arms, returns their indexes best first.
+/// Ties (E.g. proxies that were never used) are broken randomly.
+pub fn select(arms : &[&ArmStats], n : usize, traffic : Traffic) -> Vec<usize> {
+    let total_uses = arms.iter().map(|a| a.uses).sum();
+    let exploration = traffic.exploration();
+
+    let mut scored : Vec<(usize, f64)> = arms.iter()
+        .enumerate()
+        .map(|(i, a)| (i, a.ucb(total_uses, exploration)))
+        .collect();
+
+    scored.shuffle(&mut OsRng);
+    scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+    scored.into_iter().take(n).map(|(i, _)| i).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Select 1 arm per round, rewarding it by the given chance of success
+    fn simulate(success_chance : &[f64], rounds : usize, traffic : Traffic) -> Vec<usize> {
+        use rand::Rng;
+
+        let mut arms = vec![ArmStats::default(); success_chance.len()];
+        let mut picks = vec![0; success_chance.len()];
+
+        for _ in 0..rounds {
+            let refs : Vec<&ArmStats> = arms.iter().collect();
+            let i = select(&refs, 1, traffic)[0];
+            let success = OsRng.gen::<f64>() < success_chance[i];
+
+            arms[i].update(success, Duration::from_millis(200));
+            picks[i] += 1;
+        }
+
+        picks
+    }
+
+    #[test]
+    fn test_select_untried_first() {
+        let mut used = ArmStats::default();
+        used.update(true, Duration::from_millis(10));
+        let unused = ArmStats::default();
+
+        for _ in 0..10 {
+            let picked = select(&[&used, &unused, &used, &unused], 2, Traffic::Claim);
+            assert!(picked.contains(&1));
+            assert!(picked.contains(&3));
+        }
+    }
+
+    #[test]
+    fn test_select_fair_exploration() {
+        for traffic in &[Traffic::Poll, Traffic::Claim] {
+            let picks = simulate(&[1.0; 5], 1000, *traffic);
+
+            // Identical proxies share the traffic evenly
+            for p in picks {
+                assert!(p >= 150 && p <= 250, "Uneven picks {}", p);
+            }
+        }
+    }
+
+    #[test]
+    fn test_select_prefers_good() {
+        let picks = simulate(&[1.0, 0.0, 0.0, 0.0], 1000, Traffic::Poll);
+
+        assert!(picks[0] > 500, "Good proxy only picked {} times", picks[0]);
+
+        // The bad proxies are still tried now and then
+        for p in &picks[1..] {
+            assert!(*p > 0);
+        }
+    }
+
+    #[test]
+    fn test_select_recovers() {
+        let mut arms = vec![ArmStats::default(); 2];
+
+        for _ in 0..100 {
+            arms[0].update(false, Duration::from_secs(4));
+            arms[1].update(true, Duration::from_millis(200));
+        }
+
+        // Proxy 1 goes down and proxy 0 comes back
+        let mut picks = [0; 2];
+        for _ in 0..500 {
+            let refs : Vec<&ArmStats> = arms.iter().collect();
+            let i = select(&refs, 1, Traffic::Poll)[0];
+            arms[i].update(i == 0, Duration::from_millis(200));
+            picks[i] += 1;
+        }
+
+        assert!(picks[0] > picks[1]);
+    }
+}
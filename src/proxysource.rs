@@ -0,0 +1,325 @@
+//! Proxy Sources
+//!
+//! Lists of proxies read from files, directories of lists,
+//! URLs and the output of local commands.
+use crate::error::Error;
+use std::path::PathBuf;
+use std::fs;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use reqwest::Client;
+
+/// Longest a proxy list download or command may take
+pub const FETCH_TIMEOUT : Duration = Duration::from_secs(30);
+
+/// Where to get proxies from
+#[derive(Debug, Clone)]
+pub enum ProxySource {
+    /// File with 1 proxy per line
+    File(PathBuf),
+    /// Directory of proxy files, every file in it is read
+    Dir(PathBuf),
+    /// Proxy list downloaded on a schedule
+    Url(String),
+    /// Command run with sh on a schedule that prints 1 proxy per line
+    Command(String),
+}
+
+impl std::fmt::Display for ProxySource {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ProxySource::File(p) => write!(f, "file:{}", p.display()),
+            ProxySource::Dir(p) => write!(f, "dir:{}", p.display()),
+            ProxySource::Url(u) => write!(f, "url:{}", u),
+            ProxySource::Command(c) => write!(f, "cmd:{}", c),
+        }
+    }
+}
+
+/// A proxy url and the list it came from
+pub struct SourcedProxy {
+    pub url : String,
+    pub tag : String,
+}
+
+/// Download of a URL source or run of a command source,
+/// made apart from its reader so it doesn't hold up the other sources
+pub struct Fetch {
+    source : ProxySource,
+}
+
+impl Fetch {
+    /// Fails if it takes longer than timeout
+    pub fn run(&self, timeout : Duration) -> Result<Vec<SourcedProxy>, Error> {
+        let text = match &self.source {
+            ProxySource::Url(url) => {
+                let client = Client::builder()
+                    .timeout(timeout)
+                    .build()?;
+
+                client.get(url.as_str())
+                    .send()?
+                    .error_for_status()?
+                    .text()?
+            },
+            ProxySource::Command(cmd) => run_command(cmd, timeout)?,
+            source => return Err(Error::Msg(format!("Proxy source {} is not fetched", source))),
+        };
+
+        Ok(parse_list(&text, &self.source.to_string()))
+    }
+}
+
+/// Run a command with sh and return what it printed, the command is killed after timeout
+fn run_command(cmd : &str, timeout : Duration) -> Result<String, Error> {
+    let mut child = Command::new("sh").arg("-c").arg(cmd)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // Read while it runs so it can't block on a full pipe
+    let read_all = |mut pipe : Box<dyn Read + Send>| thread::spawn(move || {
+        let mut out = Vec::new();
+        pipe.read_to_end(&mut out).map(|_| String::from_utf8_lossy(&out).into_owned())
+    });
+    let stdout = read_all(Box::new(child.stdout.take().unwrap()));
+    let stderr = read_all(Box::new(child.stderr.take().unwrap()));
+
+    let start = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+
+        if start.elapsed() >= timeout {
+            child.kill().ok();
+            child.wait().ok();
+
+            return Err(Error::Msg(format!("Proxy command timed out after {}s", timeout.as_secs_f64())));
+        }
+
+        thread::sleep(Duration::from_millis(50));
+    };
+
+    let stdout = stdout.join().unwrap()?;
+    let stderr = stderr.join().unwrap()?;
+
+    if ! status.success() {
+        return Err(Error::Msg(format!("Proxy command exited with {}: {}", status, stderr.trim())));
+    }
+
+    Ok(stdout)
+}
+
+/// Reads a source when it changes (files and directories)
+/// or when it is due (URLs and commands)
+pub struct SourceReader {
+    source : ProxySource,
+    refresh : Duration,
+    /// Files read and their modification times
+    files_modified : Vec<(PathBuf, SystemTime)>,
+    last_fetch : Option<Instant>,
+    fetching : bool,
+    proxies : Vec<SourcedProxy>,
+}
+
+impl SourceReader {
+    pub fn new(source : ProxySource, refresh : Duration) -> Self {
+        Self {
+            source,
+            refresh,
+            files_modified : Vec::new(),
+            last_fetch : None,
+            fetching : false,
+            proxies : Vec::new(),
+        }
+    }
+
+    pub fn source(&self) -> &ProxySource {
+        &self.source
+    }
+
+    /// Proxies from the last successful read
+    pub fn proxies(&self) -> &[SourcedProxy] {
+        &self.proxies
+    }
+
+    /// Read a file or directory source again if it changed, returns true if it was read.
+    /// The proxies from the last read are kept if this fails.
+    pub fn refresh_files(&mut self) -> Result<bool, Error> {
+        match &self.source {
+            ProxySource::File(path) => {
+                let files = vec![(path.clone(), fs::metadata(path)?.modified()?)];
+
+                self.read_files(files)
+            },
+            ProxySource::Dir(path) => {
+                let mut files = Vec::new();
+
+                for entry in fs::read_dir(path)? {
+                    let entry = entry?;
+                    let meta = entry.metadata()?;
+
+                    if meta.is_file() {
+                        files.push((entry.path(), meta.modified()?));
+                    }
+                }
+
+                files.sort();
+
+                self.read_files(files)
+            },
+            ProxySource::Url(_) | ProxySource::Command(_) => Ok(false),
+        }
+    }
+
+    /// The fetch to run if this is a URL or command source that is due
+    /// and isn't already being fetched, give its result to fetched
+    pub fn start_fetch(&mut self) -> Option<Fetch> {
+        match &self.source {
+            ProxySource::Url(_) | ProxySource::Command(_) if ! self.fetching && self.due() => {
+                self.last_fetch = Some(Instant::now());
+                self.fetching = true;
+
+                Some(Fetch { source : self.source.clone() })
+            },
+            _ => None,
+        }
+    }
+
+    /// Keep the proxies of a finished fetch, returns true if it succeeded.
+    /// The proxies from the last fetch are kept if it failed.
+    pub fn fetched(&mut self, result : Result<Vec<SourcedProxy>, Error>) -> Result<bool, Error> {
+        self.fetching = false;
+        self.proxies = result?;
+
+        Ok(true)
+    }
+
+    fn due(&self) -> bool {
+        self.last_fetch.map(|t| t.elapsed() >= self.refresh).unwrap_or(true)
+    }
+
+    fn read_files(&mut self, files : Vec<(PathBuf, SystemTime)>) -> Result<bool, Error> {
+        if files == self.files_modified {
+            return Ok(false);
+        }
+
+        let mut proxies = Vec::new();
+        for (path, _) in &files {
+            let tag = match &self.source {
+                ProxySource::Dir(_) => format!("file:{}", path.display()),
+                source => source.to_string(),
+            };
+
+            proxies.extend(parse_list(&fs::read_to_string(path)?, &tag));
+        }
+
+        self.proxies = proxies;
+        self.files_modified = files;
+
+        Ok(true)
+    }
+}
+
+/// 1 proxy per line, empty lines and lines starting with # are skipped
+fn parse_list(text : &str, tag : &str) -> Vec<SourcedProxy> {
+    text.lines()
+        .map(|l| l.trim())
+        .filter(|l| ! l.is_empty() && ! l.starts_with('#'))
+        .map(|l| SourcedProxy {
+            url : String::from(l),
+            tag : String::from(tag),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Fetch now if due, returns true if fetched
+    fn fetch_now(reader : &mut SourceReader) -> Result<bool, Error> {
+        match reader.start_fetch() {
+            Some(fetch) => reader.fetched(fetch.run(FETCH_TIMEOUT)),
+            None => Ok(false),
+        }
+    }
+
+    fn urls(reader : &SourceReader) -> Vec<(&str, &str)> {
+        reader.proxies().iter().map(|p| (p.url.as_str(), p.tag.as_str())).collect()
+    }
+
+    #[test]
+    fn test_source_dir_ok() {
+        let dir = std::env::temp_dir().join(format!("cpen442-proxy-dir-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "# list a\nhttp://1.1.1.1:80\n\n").unwrap();
+        fs::write(dir.join("b.txt"), " socks5://2.2.2.2:1080 \n").unwrap();
+
+        let mut reader = SourceReader::new(ProxySource::Dir(dir.clone()), Duration::from_secs(600));
+        assert!(reader.refresh_files().unwrap());
+
+        let a_tag = format!("file:{}", dir.join("a.txt").display());
+        let b_tag = format!("file:{}", dir.join("b.txt").display());
+        assert_eq!(urls(&reader), vec![
+            ("http://1.1.1.1:80", a_tag.as_str()),
+            ("socks5://2.2.2.2:1080", b_tag.as_str()),
+        ]);
+
+        // Unchanged so not read again
+        assert!(! reader.refresh_files().unwrap());
+
+        fs::remove_file(dir.join("b.txt")).unwrap();
+        assert!(reader.refresh_files().unwrap());
+        assert_eq!(urls(&reader), vec![("http://1.1.1.1:80", a_tag.as_str())]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_source_command_ok() {
+        let cmd = "printf 'http://1.1.1.1:80\\nhttp://3.3.3.3:8080\\n'";
+        let mut reader = SourceReader::new(ProxySource::Command(cmd.into()), Duration::from_secs(600));
+
+        assert!(fetch_now(&mut reader).unwrap());
+        let tag = format!("cmd:{}", cmd);
+        assert_eq!(urls(&reader), vec![
+            ("http://1.1.1.1:80", tag.as_str()),
+            ("http://3.3.3.3:8080", tag.as_str()),
+        ]);
+
+        // Not due to run again yet
+        assert!(! fetch_now(&mut reader).unwrap());
+    }
+
+    #[test]
+    fn test_source_command_bad() {
+        let mut reader = SourceReader::new(ProxySource::Command("exit 3".into()), Duration::from_secs(0));
+
+        assert!(fetch_now(&mut reader).is_err());
+        assert!(reader.proxies().is_empty());
+
+        // Killed once it takes too long
+        let start = Instant::now();
+        let fetch = Fetch { source : ProxySource::Command("sleep 10".into()) };
+        assert!(fetch.run(Duration::from_millis(200)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_source_fetch_once() {
+        let mut reader = SourceReader::new(ProxySource::Command("echo http://1.1.1.1:80".into()),
+            Duration::from_secs(0));
+
+        // Not fetched again until the running fetch is done
+        let fetch = reader.start_fetch().unwrap();
+        assert!(reader.start_fetch().is_none());
+        assert!(reader.fetched(fetch.run(FETCH_TIMEOUT)).unwrap());
+        assert_eq!(reader.proxies().len(), 1);
+        assert!(reader.start_fetch().is_some());
+    }
+}
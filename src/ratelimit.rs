@@ -0,0 +1,165 @@
+//! Rate Limiting
+//!
+//! Token buckets that keep each client under the tracker's
+//! request quota, one bucket per endpoint.
+use crate::faults::TrackerOp;
+use std::time::Instant;
+
+/// Requests per minute allowed on each tracker endpoint
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimits {
+    pub last_coin : f64,
+    pub difficulty : f64,
+    pub claim_coin : f64,
+}
+
+impl std::str::FromStr for RateLimits {
+    type Err = String;
+
+    /// Parsed from `last_coin,difficulty,claim_coin` requests per minute, e.g. `5,2,10`
+    fn from_str(s : &str) -> Result<Self, Self::Err> {
+        let limits = s.split(',')
+            .map(|l| l.trim().parse::<f64>().map_err(|e| format!("Bad rate limit \"{}\": {}", l, e)))
+            .collect::<Result<Vec<f64>, String>>()?;
+
+        if limits.len() != 3 {
+            return Err(format!("Expected last_coin,difficulty,claim_coin limits, got \"{}\"", s));
+        }
+
+        if limits.iter().any(|l| *l <= 0.0) {
+            return Err(format!("Rate limits should be above 0, got \"{}\"", s));
+        }
+
+        Ok(RateLimits {
+            last_coin : limits[0],
+            difficulty : limits[1],
+            claim_coin : limits[2],
+        })
+    }
+}
+
+/// Allows bursts of up to a minute's worth of requests
+/// and refills continuously at the per minute rate.
+struct TokenBucket {
+    capacity : f64,
+    tokens : f64,
+    per_sec : f64,
+    last : Instant,
+}
+
+impl TokenBucket {
+    fn new(per_minute : f64) -> Self {
+        Self {
+            capacity : per_minute,
+            tokens : per_minute,
+            per_sec : per_minute / 60.0,
+            last : Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now : Instant) {
+        if now > self.last {
+            let elapsed = (now - self.last).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.per_sec).min(self.capacity);
+            self.last = now;
+        }
+    }
+
+    fn ready(&mut self, now : Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= 1.0
+    }
+
+    fn take(&mut self, now : Instant) -> bool {
+        if self.ready(now) {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Rate limiter for a single client
+pub struct RateLimiter {
+    last_coin : TokenBucket,
+    difficulty : TokenBucket,
+    claim_coin : TokenBucket,
+}
+
+impl RateLimiter {
+    pub fn new(limits : &RateLimits) -> Self {
+        Self {
+            last_coin : TokenBucket::new(limits.last_coin),
+            difficulty : TokenBucket::new(limits.difficulty),
+            claim_coin : TokenBucket::new(limits.claim_coin),
+        }
+    }
+
+    fn bucket(&mut self, op : TrackerOp) -> &mut TokenBucket {
+        match op {
+            TrackerOp::LastCoin => &mut self.last_coin,
+            TrackerOp::Difficulty => &mut self.difficulty,
+            TrackerOp::ClaimCoin => &mut self.claim_coin,
+        }
+    }
+
+    /// Check if a request can be made without using it up
+    pub fn ready(&mut self, op : TrackerOp) -> bool {
+        self.bucket(op).ready(Instant::now())
+    }
+
+    /// Use up a request if one is available
+    pub fn try_acquire(&mut self, op : TrackerOp) -> bool {
+        self.bucket(op).take(Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket_ok() {
+        let mut bucket = TokenBucket::new(6.0);
+        let start = bucket.last;
+
+        for _ in 0..6 {
+            assert!(bucket.take(start));
+        }
+        assert!(! bucket.take(start));
+
+        // 6 per minute is 1 every 10 seconds
+        assert!(! bucket.take(start + Duration::from_secs(9)));
+        assert!(bucket.take(start + Duration::from_secs(10)));
+        assert!(! bucket.take(start + Duration::from_secs(10)));
+
+        // Never refills past the burst size
+        let later = start + Duration::from_secs(3600);
+        for _ in 0..6 {
+            assert!(bucket.take(later));
+        }
+        assert!(! bucket.take(later));
+    }
+
+    #[test]
+    fn test_rate_limiter_endpoints_ok() {
+        let mut limiter = RateLimiter::new(&"1,1,2".parse().unwrap());
+
+        assert!(limiter.try_acquire(TrackerOp::LastCoin));
+        assert!(! limiter.ready(TrackerOp::LastCoin));
+        assert!(limiter.ready(TrackerOp::Difficulty));
+        assert!(limiter.try_acquire(TrackerOp::ClaimCoin));
+        assert!(limiter.try_acquire(TrackerOp::ClaimCoin));
+        assert!(! limiter.try_acquire(TrackerOp::ClaimCoin));
+    }
+
+    #[test]
+    fn test_rate_limits_parse_bad() {
+        assert!("5,2".parse::<RateLimits>().is_err());
+        assert!("5,2,a".parse::<RateLimits>().is_err());
+        assert!("5,0,10".parse::<RateLimits>().is_err());
+    }
+}
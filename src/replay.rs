@@ -0,0 +1,395 @@
+//! Tracker Record and Replay
+//!
+//! Records every tracker request made while mining to a file,
+//! then feeds the recorded responses back with the same timing
+//! so problems seen in production can be reproduced offline.
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use serde_json;
+use crate::error::Error;
+use crate::cpen442coin::CoinTracker;
+
+/// Errors as they are stored in a recording
+#[derive(Serialize, Deserialize, Clone)]
+enum RecordedError {
+    BadCoin(String),
+    ServerBusy,
+    Timeout,
+    AllRequestsFailed(String),
+    Other(String),
+}
+
+impl From<&Error> for RecordedError {
+    fn from(e : &Error) -> Self {
+        match e {
+            Error::BadCoin(s) => RecordedError::BadCoin(s.clone()),
+            Error::ServerBusy => RecordedError::ServerBusy,
+            Error::Timeout => RecordedError::Timeout,
+            Error::AllRequestsFailed(s) => RecordedError::AllRequestsFailed(s.clone()),
+            e => RecordedError::Other(format!("{:?}", e)),
+        }
+    }
+}
+
+impl From<RecordedError> for Error {
+    fn from(e : RecordedError) -> Self {
+        match e {
+            RecordedError::BadCoin(s) => Error::BadCoin(s),
+            RecordedError::ServerBusy => Error::ServerBusy,
+            RecordedError::Timeout => Error::Timeout,
+            RecordedError::AllRequestsFailed(s) => Error::AllRequestsFailed(s),
+            RecordedError::Other(s) => Error::Msg(s),
+        }
+    }
+}
+
+/// A HTTP response as it is stored in a recording
+#[derive(Serialize, Deserialize, Clone)]
+struct HttpResponse {
+    status : u16,
+    body : String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum TrackerCall {
+    /// A HTTP request made by the tracker, directly or through a proxy,
+    /// kept for debugging and not replayed
+    Http {
+        url : String,
+        via : String,
+        result : Result<HttpResponse, RecordedError>,
+    },
+    LastCoin { result : Result<String, RecordedError> },
+    Difficulty { result : Result<u64, RecordedError> },
+    Claim {
+        previous_coin : String,
+        hash : String,
+        result : Result<(), RecordedError>,
+    },
+}
+
+/// A single line in a recording
+#[derive(Serialize, Deserialize)]
+struct TrackerRecord {
+    /// Milliseconds since the start of mining the request was made
+    ms : u64,
+    /// How long the request took in milliseconds
+    took_ms : u64,
+    call : TrackerCall,
+}
+
+/// Writes the records of a recording, shared with trackers that
+/// record the HTTP requests made in their own threads
+pub struct Recorder {
+    file : Mutex<File>,
+    start : Mutex<Instant>,
+}
+
+impl Recorder {
+    fn new(filepath : PathBuf) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(filepath)?;
+
+        Ok(Self {
+            file : Mutex::new(file),
+            start : Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Record times from now on
+    fn restart(&self) {
+        *self.start.lock().unwrap() = Instant::now();
+    }
+
+    /// Write the record of a request that started at req_start and just finished.
+    /// Failing to write is logged, it shouldn't stop the mining.
+    fn record(&self, req_start : Instant, call : TrackerCall) {
+        let start = *self.start.lock().unwrap();
+        let record = TrackerRecord {
+            ms : req_start.saturating_duration_since(start).as_millis() as u64,
+            took_ms : req_start.elapsed().as_millis() as u64,
+            call,
+        };
+
+        let written = serde_json::to_string(&record).map_err(Error::from).and_then(|mut line| {
+            line.push('\n');
+
+            let mut file = self.file.lock().unwrap();
+            file.write_all(line.as_bytes())?;
+            file.flush()?;
+
+            Ok(())
+        });
+
+        if let Err(e) = written {
+            println!("\nFailed to write tracker record: {:?}", e);
+        }
+    }
+
+    /// Record a HTTP request, via is the proxy it went through
+    pub fn record_http(&self, req_start : Instant, url : &str, via : &str,
+        result : Result<(u16, &str), &Error>) {
+        self.record(req_start, TrackerCall::Http {
+            url : String::from(url),
+            via : String::from(via),
+            result : result
+                .map(|(status, body)| HttpResponse { status, body : String::from(body) })
+                .map_err(RecordedError::from),
+        });
+    }
+}
+
+/// Wraps a tracker and records all its requests
+pub struct RecordingTracker<T : CoinTracker> {
+    tracker : T,
+    recorder : Arc<Recorder>,
+}
+
+impl<T : CoinTracker> RecordingTracker<T> {
+    pub fn new(mut tracker : T, filepath : PathBuf) -> Result<Self, Error> {
+        let recorder = Arc::new(Recorder::new(filepath)?);
+        tracker.set_recorder(recorder.clone());
+
+        Ok(Self {
+            tracker,
+            recorder,
+        })
+    }
+
+    fn record(&self, req_start : Instant, call : TrackerCall) {
+        self.recorder.record(req_start, call);
+    }
+}
+
+impl<T : CoinTracker> CoinTracker for RecordingTracker<T> {
+    fn start(&mut self, poll_ms : u32) {
+        self.recorder.restart();
+        self.tracker.start(poll_ms);
+    }
+
+    fn id(&self) -> &str {
+        self.tracker.id()
+    }
+
+    fn last_coin(&mut self) -> Result<String, Error> {
+        let req_start = Instant::now();
+        let result = self.tracker.last_coin();
+
+        self.record(req_start, TrackerCall::LastCoin {
+            result : result.as_ref().map(|c| c.clone()).map_err(RecordedError::from),
+        });
+
+        result
+    }
+
+    fn difficulty(&mut self) -> Result<u64, Error> {
+        let req_start = Instant::now();
+        let result = self.tracker.difficulty();
+
+        self.record(req_start, TrackerCall::Difficulty {
+            result : result.as_ref().map(|d| *d).map_err(RecordedError::from),
+        });
+
+        result
+    }
+
+    fn claim(&mut self, blob : Vec<u8>, previous_coin : String, hash : &str) -> Result<(), Error> {
+        let req_start = Instant::now();
+        let result = self.tracker.claim(blob, previous_coin.clone(), hash);
+
+        self.record(req_start, TrackerCall::Claim {
+            previous_coin,
+            hash : String::from(hash),
+            result : result.as_ref().map(|_| ()).map_err(RecordedError::from),
+        });
+
+        result
+    }
+}
+
+/// Recorded response and how long it took in milliseconds
+type Timed<R> = (u64, Result<R, RecordedError>);
+
+/// Plays back a recording made by `RecordingTracker`
+///
+/// Last coin and difficulty requests get the most recent recorded
+/// response for the time since mining started. Claims get the recorded
+/// claim responses in order, no matter what coin is being claimed.
+/// Each response takes as long as the recorded request did.
+pub struct ReplayTracker {
+    miner_id : String,
+    last_coins : VecDeque<(u64, Timed<String>)>,
+    difficulties : VecDeque<(u64, Timed<u64>)>,
+    claims : VecDeque<Timed<()>>,
+    start : Instant,
+}
+
+impl ReplayTracker {
+    pub fn new(miner_id : String, filepath : PathBuf) -> Result<Self, Error> {
+        let reader = BufReader::new(File::open(filepath)?);
+        let mut last_coins = VecDeque::new();
+        let mut difficulties = VecDeque::new();
+        let mut claims = VecDeque::new();
+
+        for line in reader.lines() {
+            let line = line?;
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let record : TrackerRecord = serde_json::from_str(&line)?;
+
+            match record.call {
+                TrackerCall::Http { .. } => {},
+                TrackerCall::LastCoin { result } =>
+                    last_coins.push_back((record.ms, (record.took_ms, result))),
+                TrackerCall::Difficulty { result } =>
+                    difficulties.push_back((record.ms, (record.took_ms, result))),
+                TrackerCall::Claim { result, .. } => claims.push_back((record.took_ms, result)),
+            }
+        }
+
+        if last_coins.is_empty() || difficulties.is_empty() {
+            return Err(Error::Msg("Recording has no last coin or difficulty responses".into()));
+        }
+
+        Ok(Self {
+            miner_id,
+            last_coins,
+            difficulties,
+            claims,
+            start : Instant::now(),
+        })
+    }
+
+    /// The latest response recorded at or before now,
+    /// or the first response if mining started sooner than in the recording.
+    fn response_at<R : Clone>(responses : &mut VecDeque<(u64, R)>, now_ms : u64) -> R {
+        while responses.len() > 1 && responses[1].0 <= now_ms {
+            responses.pop_front();
+        }
+
+        responses.front().unwrap().1.clone()
+    }
+
+    /// Take as long as the recorded request
+    fn respond<R>((took_ms, result) : Timed<R>) -> Result<R, Error> {
+        thread::sleep(Duration::from_millis(took_ms));
+
+        Ok(result?)
+    }
+}
+
+impl CoinTracker for ReplayTracker {
+    fn start(&mut self, _poll_ms : u32) {
+        self.start = Instant::now();
+    }
+
+    fn id(&self) -> &str {
+        &self.miner_id
+    }
+
+    fn last_coin(&mut self) -> Result<String, Error> {
+        let now_ms = self.start.elapsed().as_millis() as u64;
+
+        Self::respond(Self::response_at(&mut self.last_coins, now_ms))
+    }
+
+    fn difficulty(&mut self) -> Result<u64, Error> {
+        let now_ms = self.start.elapsed().as_millis() as u64;
+
+        Self::respond(Self::response_at(&mut self.difficulties, now_ms))
+    }
+
+    fn claim(&mut self, _blob : Vec<u8>, _previous_coin : String, _hash : &str) -> Result<(), Error> {
+        match self.claims.pop_front() {
+            Some(timed) => Self::respond(timed),
+            None => Err(Error::Msg("No more recorded claims to replay".into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct StubTracker {
+        claims : u32,
+        recorder : Option<Arc<Recorder>>,
+    }
+
+    impl CoinTracker for StubTracker {
+        fn set_recorder(&mut self, recorder : Arc<Recorder>) {
+            self.recorder = Some(recorder);
+        }
+
+        fn id(&self) -> &str {
+            "stub"
+        }
+
+        fn last_coin(&mut self) -> Result<String, Error> {
+            let body = r#"{"coin_id": "00000000008c70b237c12e2c25d278cc"}"#;
+            self.recorder.as_ref().unwrap().record_http(Instant::now(), "http://stub/last_coin", "direct",
+                Ok((200, body)));
+
+            Ok("00000000008c70b237c12e2c25d278cc".into())
+        }
+
+        fn difficulty(&mut self) -> Result<u64, Error> {
+            Err(Error::ServerBusy)
+        }
+
+        fn claim(&mut self, _blob : Vec<u8>, _previous_coin : String, _hash : &str) -> Result<(), Error> {
+            self.claims += 1;
+
+            if self.claims == 1 {
+                Ok(())
+            } else {
+                thread::sleep(Duration::from_millis(100));
+                Err(Error::BadCoin("Too slow".into()))
+            }
+        }
+    }
+
+    #[test]
+    fn test_record_replay_ok() {
+        let path = std::env::temp_dir().join(format!("cpen442-replay-{}.jsonl", std::process::id()));
+
+        let stub = StubTracker { claims : 0, recorder : None };
+        let mut recorder = RecordingTracker::new(stub, path.clone()).unwrap();
+        recorder.start(1000);
+        recorder.last_coin().unwrap();
+        assert!(recorder.difficulty().is_err());
+        recorder.claim(vec![1, 2, 3], "a".into(), "b").unwrap();
+        assert!(recorder.claim(vec![1, 2, 3], "a".into(), "b").is_err());
+
+        // The tracker's own HTTP requests are recorded too
+        let recording = std::fs::read_to_string(&path).unwrap();
+        assert!(recording.contains(r#""op":"http","url":"http://stub/last_coin""#));
+
+        let mut replayer = ReplayTracker::new("stub".into(), path.clone()).unwrap();
+        replayer.start(1000);
+        assert_eq!(replayer.last_coin().unwrap(), "00000000008c70b237c12e2c25d278cc");
+        assert!(match replayer.difficulty() { Err(Error::ServerBusy) => true, _ => false });
+        assert!(replayer.claim(vec![], "".into(), "").is_ok());
+
+        // Responses take as long as they did when recorded
+        let start = Instant::now();
+        assert!(match replayer.claim(vec![], "".into(), "") { Err(Error::BadCoin(_)) => true, _ => false });
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        assert!(replayer.claim(vec![], "".into(), "").is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}